@@ -0,0 +1,140 @@
+//! Compare hashing and building release archives on a single thread with
+//! doing so on several threads
+//!
+//! Run with `cargo bench --bench archive`
+
+use cargo_rpm::{
+    archive::{Archive, SourceDirs},
+    compression::Compression,
+    config::PackageConfig,
+    fingerprint::FingerprintHasher,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Size of the (semi-compressible) binary in the archive
+const BINARY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Number and size of the data files in the archive
+const DATA_FILES: usize = 256;
+const DATA_FILE_SIZE: usize = 128 * 1024;
+
+/// Thread counts to compare against a single thread
+const THREADS: &[u32] = &[2, 4, 8];
+
+fn main() {
+    let dir = std::env::temp_dir().join("cargo-rpm-bench");
+    let sources = SourceDirs {
+        crate_root: dir.clone(),
+        config: dir.join("config"),
+        target: dir.join("target"),
+        out_dirs: BTreeMap::new(),
+    };
+
+    create_sources(&sources);
+    let archive = Archive::new(&package_config(), &sources, &[]).unwrap();
+
+    println!("{} CPUs", num_cpus::get());
+
+    compare("fingerprint", |threads| {
+        let started = Instant::now();
+        let mut hasher = FingerprintHasher::new();
+        archive
+            .fingerprint(&mut hasher, threads.unwrap_or(1))
+            .unwrap();
+        hasher.finish();
+        started.elapsed()
+    });
+
+    for codec in &["gzip:6", "zstd:9", "xz:6"] {
+        let compression: Compression = codec.parse().unwrap();
+        let output = dir.join("archive.tar");
+
+        compare(codec, |threads| {
+            let started = Instant::now();
+            archive.build(&output, compression, threads).unwrap();
+            started.elapsed()
+        });
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Time an operation without threads (`None`), then with each number of
+/// threads, printing the speedup of each
+fn compare(name: &str, mut run: impl FnMut(Option<u32>) -> Duration) {
+    let serial = run(None);
+    println!("{:>12} serial:    {:>8.3}s", name, serial.as_secs_f64());
+
+    for &threads in THREADS {
+        let elapsed = run(Some(threads));
+
+        println!(
+            "{:>12} threads={}: {:>8.3}s ({:.2}x)",
+            name,
+            threads,
+            elapsed.as_secs_f64(),
+            serial.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
+
+/// Package config with a binary target and a directory of data files
+fn package_config() -> PackageConfig {
+    serde_json::from_str(
+        r#"{
+            "name": "bench",
+            "version": "1.0.0",
+            "description": "Benchmark package",
+            "license": "MIT",
+            "metadata": {
+                "rpm": {
+                    "targets": { "bench": { "path": "/usr/bin/bench" } },
+                    "files": { "data": { "path": "/usr/share/bench" } }
+                }
+            }
+        }"#,
+    )
+    .unwrap()
+}
+
+/// Write the binary and data files the package config refers to
+fn create_sources(sources: &SourceDirs) {
+    let data_dir = sources.config.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    fs::create_dir_all(&sources.target).unwrap();
+
+    write_file(&sources.target.join("bench"), BINARY_SIZE, 1);
+
+    for i in 0..DATA_FILES {
+        write_file(
+            &data_dir.join(format!("{}.dat", i)),
+            DATA_FILE_SIZE,
+            i as u64 + 2,
+        );
+    }
+}
+
+/// Write a file of pseudo-random data with enough repetition to be
+/// compressible, roughly resembling a binary
+fn write_file(path: &Path, size: usize, seed: u64) {
+    let mut state = 0x2545_f491_4f6c_dd1d ^ seed;
+    let mut data = Vec::with_capacity(size);
+
+    while data.len() < size {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+
+        let byte = (state % 16) as u8;
+        let run = 1 + (state >> 60) as usize;
+        data.resize(data.len() + run, byte);
+    }
+
+    data.truncate(size);
+    fs::write(path, data).unwrap();
+}
@@ -4,18 +4,23 @@
 //! Rust support for tar is presently (as of writing) better.
 
 use crate::{
+    compression::Compression,
     error::{Error, ErrorKind},
+    fingerprint::{self, FingerprintHasher},
     prelude::*,
 };
-use flate2::{write::GzEncoder, Compression};
+use glob::{MatchOptions, Pattern};
 use std::{
-    fs::{File, OpenOptions},
-    path::{Path, PathBuf},
-    time::UNIX_EPOCH,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
-use tar::{Builder, Header};
+use tar::{Builder, EntryType, Header};
 
-use crate::config::{FileConfig, PackageConfig};
+use crate::config::{FileConfig, FileType, PackageConfig};
 
 /// Default user that owns files in the archive
 const DEFAULT_USERNAME: &str = "root";
@@ -29,6 +34,54 @@ const DEFAULT_TARGET_MODE: u32 = 0o755;
 /// Default permissions for other files in the archive
 const DEFAULT_FILE_MODE: u32 = 0o644;
 
+/// Default permissions for directories owned by the package
+const DEFAULT_DIR_MODE: u32 = 0o755;
+
+/// Permissions of symbolic links
+const SYMLINK_MODE: u32 = 0o777;
+
+/// Characters with a special meaning in glob patterns
+const GLOB_META_CHARS: &[char] = &['*', '?', '['];
+
+/// How glob patterns match paths: `*` and `?` match neither `/` nor the `.`
+/// at the start of a hidden file's name
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: true,
+};
+
+/// Default umask applied to the permissions of extra files
+const DEFAULT_UMASK: u32 = 0o022;
+
+/// Directories packages shouldn't install into, and what they're for
+const RESERVED_DIRS: &[(&str, &str)] = &[
+    ("/usr/lib/debug", "debuginfo packages"),
+    ("/dev", "device nodes"),
+    ("/proc", "the kernel"),
+    ("/sys", "the kernel"),
+    ("/run", "runtime state (use tmpfiles.d)"),
+    ("/var/run", "runtime state (use tmpfiles.d)"),
+    ("/tmp", "temporary files"),
+    ("/var/tmp", "temporary files"),
+];
+
+/// Kinds of entries in the release archive
+#[derive(Debug)]
+enum EntryKind {
+    /// Regular file copied from the source path
+    File,
+
+    /// Directory owned by the package
+    Dir,
+
+    /// Symbolic link pointing at the given target
+    Symlink(PathBuf),
+
+    /// Hard link to an earlier entry in the archive (by its archive path)
+    Hardlink(PathBuf),
+}
+
 /// Files within the release archive
 #[derive(Debug)]
 pub struct ArchiveFile {
@@ -38,6 +91,15 @@ pub struct ArchiveFile {
     /// Path to use in the resulting archive (absolute)
     archive_path: PathBuf,
 
+    /// Path where the file is installed
+    install_path: PathBuf,
+
+    /// Kind of archive entry
+    kind: EntryKind,
+
+    /// Cargo.toml key of the entry this file comes from
+    config_key: String,
+
     /// User that owns the given file
     pub username: String,
 
@@ -46,14 +108,19 @@ pub struct ArchiveFile {
 
     /// Mode of the file
     pub mode: u32,
+
+    /// Is this a config file which upgrades keep if it was modified?
+    pub noreplace: bool,
 }
 
 impl ArchiveFile {
-    /// Create a new archive file with the given config
+    /// Create a new archive file with the given config (taken from the given
+    /// Cargo.toml key)
     pub fn new(
         src_path: &Path,
         base_dir: &Path,
         file_config: &FileConfig,
+        config_key: &str,
         default_mode: u32,
     ) -> Result<Self, Error> {
         let archive_path = base_dir.join(file_config.path.strip_prefix("/")?);
@@ -68,24 +135,120 @@ impl ArchiveFile {
             None => DEFAULT_GROUPNAME.to_owned(),
         };
 
-        let mode = match file_config.mode {
-            Some(ref m) => u32::from_str_radix(m, 8)?,
-            None => default_mode,
+        let kind = match (&file_config.link_target, file_config.file_type) {
+            (Some(target), _) => EntryKind::Symlink(target.clone()),
+            (None, Some(FileType::Dir)) => EntryKind::Dir,
+            (None, _) => EntryKind::File,
+        };
+
+        let mode = match (file_config.mode.as_ref(), &kind) {
+            (Some(m), _) => parse_mode(m, &file_config.path.display())?,
+            (None, EntryKind::Symlink(_)) => SYMLINK_MODE,
+            (None, _) => default_mode,
         };
 
         Ok(Self {
             src_path: src_path.to_owned(),
             archive_path,
+            install_path: file_config.path.clone(),
+            kind,
+            config_key: config_key.to_owned(),
             username,
             groupname,
             mode,
+            noreplace: false,
         })
     }
 
+    /// Add this file's path, ownership, mode and contents to a fingerprint,
+    /// given the digest of its contents if it's a regular file
+    fn fingerprint(&self, hasher: &mut FingerprintHasher, content_digest: Option<Vec<u8>>) {
+        hasher
+            .field(self.archive_path.to_string_lossy().as_bytes())
+            .field(&self.username)
+            .field(&self.groupname)
+            .field(self.mode.to_le_bytes());
+
+        match self.kind {
+            EntryKind::Symlink(ref target) => {
+                hasher
+                    .field("symlink")
+                    .field(target.to_string_lossy().as_bytes());
+            }
+            EntryKind::Hardlink(ref target) => {
+                hasher
+                    .field("hardlink")
+                    .field(target.to_string_lossy().as_bytes());
+            }
+            EntryKind::Dir => {
+                hasher.field("directory");
+            }
+            EntryKind::File => {
+                hasher.field(content_digest.unwrap_or_default());
+            }
+        }
+    }
+
     /// Append this file to the given archive builder
-    pub fn append_to(&self, builder: &mut Builder<GzEncoder<File>>) -> Result<(), Error> {
+    pub fn append_to<W: Write>(&self, builder: &mut Builder<W>) -> Result<(), Error> {
         let mut header = Header::new_gnu();
 
+        let (entry_type, link_target) = match self.kind {
+            EntryKind::Symlink(ref target) => (EntryType::Symlink, Some(target)),
+            EntryKind::Hardlink(ref target) => (EntryType::Link, Some(target)),
+            EntryKind::Dir => (EntryType::Directory, None),
+            EntryKind::File => return self.append_file_to(builder, header),
+        };
+
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+        header.set_username(&self.username)?;
+        header.set_groupname(&self.groupname)?;
+        header.set_mode(self.mode);
+
+        match link_target {
+            Some(target) => builder.append_link(&mut header, &self.archive_path, target)?,
+            None => builder.append_data(&mut header, &self.archive_path, io::empty())?,
+        }
+
+        Ok(())
+    }
+
+    /// Is this a directory owned by the package?
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind, EntryKind::Dir)
+    }
+
+    /// Spec `%files` entry for this file, with its ownership and mode (which
+    /// rpm doesn't let symlinks have)
+    pub fn files_entry(&self) -> String {
+        let mode = match self.kind {
+            EntryKind::Symlink(_) => "-".to_owned(),
+            _ => format!("{:04o}", self.mode),
+        };
+
+        format!(
+            "{}{}%attr({},{},{}) {}",
+            if self.is_dir() { "%dir " } else { "" },
+            if self.noreplace {
+                "%config(noreplace) "
+            } else {
+                ""
+            },
+            mode,
+            self.username,
+            self.groupname,
+            self.install_path.display()
+        )
+    }
+
+    /// Append the contents of this (regular) file to the given archive builder
+    fn append_file_to<W: Write>(
+        &self,
+        builder: &mut Builder<W>,
+        mut header: Header,
+    ) -> Result<(), Error> {
         let src_file = File::open(&self.src_path)?;
         let src_metadata = src_file.metadata()?;
         header.set_size(src_metadata.len());
@@ -101,7 +264,7 @@ impl ArchiveFile {
         header.set_cksum();
 
         if src_metadata.is_dir() {
-            builder.append_data(&mut header, &self.archive_path, std::io::empty())?;
+            builder.append_data(&mut header, &self.archive_path, io::empty())?;
         } else {
             builder.append_data(&mut header, &self.archive_path, src_file)?;
         }
@@ -109,19 +272,103 @@ impl ArchiveFile {
     }
 }
 
+/// File generated from settings in Cargo.toml (e.g. a systemd unit)
+#[derive(Clone, Debug)]
+pub struct GeneratedFile {
+    /// Path to the generated file on the local filesystem
+    pub src_path: PathBuf,
+
+    /// Path where the file is installed
+    pub install_path: PathBuf,
+
+    /// Cargo.toml key of the settings the file was generated from
+    pub config_key: String,
+
+    /// Mode of the file
+    pub mode: u32,
+
+    /// Is this a config file which upgrades keep if it was modified?
+    pub noreplace: bool,
+}
+
+/// Directories which packaged files can be taken from
+#[derive(Clone, Debug)]
+pub struct SourceDirs {
+    /// Crate root (`crate:`)
+    pub crate_root: PathBuf,
+
+    /// RPM config directory, i.e. `.rpm` (`config:`, the default)
+    pub config: PathBuf,
+
+    /// Target directory for the current profile (`target:`)
+    pub target: PathBuf,
+
+    /// `OUT_DIR` of each crate's build script (`out_dir:<crate>/`)
+    pub out_dirs: BTreeMap<String, PathBuf>,
+}
+
+impl SourceDirs {
+    /// Split a `source` such as `crate:man/foo.1` into the directory it is
+    /// relative to and the path (or glob) within it
+    pub fn resolve(&self, source: &str) -> Result<(PathBuf, String), Error> {
+        let mut parts = source.splitn(2, ':');
+        let (base, path) = match (parts.next(), parts.next()) {
+            (Some(base), Some(path)) => (base, path),
+            _ => return Ok((self.config.clone(), source.to_owned())),
+        };
+
+        let dir = match base {
+            "crate" => self.crate_root.clone(),
+            "config" => self.config.clone(),
+            "target" => self.target.clone(),
+            "out_dir" => {
+                let mut parts = path.splitn(2, '/');
+                let crate_name = parts.next().unwrap_or("");
+                let path = parts.next().unwrap_or("");
+
+                let out_dir = self.out_dirs.get(crate_name).ok_or_else(|| {
+                    format_err!(
+                        ErrorKind::Config,
+                        "no OUT_DIR known for crate {:?} in source {:?} (does it have a \
+                         build script, and was it built by `cargo rpm build`?)",
+                        crate_name,
+                        source
+                    )
+                })?;
+
+                return Ok((out_dir.clone(), path.to_owned()));
+            }
+            other => fail!(
+                ErrorKind::Config,
+                "unknown base {:?} in source {:?} (expected crate, config, target or out_dir)",
+                other,
+                source
+            ),
+        };
+
+        Ok((dir, path.to_owned()))
+    }
+}
+
 /// Tarball builder for Rust RPMs
 #[derive(Debug)]
 pub struct Archive {
     /// Files to include in the archive
     files: Vec<ArchiveFile>,
+
+    /// Number of files (at the start of `files`) which are target binaries
+    targets: usize,
+
+    /// Number of files (at the end of `files`) which were generated
+    generated: usize,
 }
 
 impl Archive {
     /// Process the package config and prepare to build the archive
     pub fn new(
         config: &PackageConfig,
-        rpm_config_dir: &Path,
-        target_dir: &Path,
+        sources: &SourceDirs,
+        generated: &[GeneratedFile],
     ) -> Result<Self, Error> {
         let (version, _) = config.version();
         let base_dir = PathBuf::from(format!("{}-{}", config.rpm_name(), version));
@@ -132,66 +379,804 @@ impl Archive {
             )
         })?;
 
-        let mut archive_files: Vec<ArchiveFile> = rpm_metadata
-            .targets
-            .iter()
-            .map(|(name, config)| {
-                ArchiveFile::new(
-                    &target_dir.join(name),
+        let umask = match rpm_metadata.umask {
+            Some(ref umask) => parse_mode(umask, &"umask")?,
+            None => DEFAULT_UMASK,
+        };
+
+        let mut archive_files = vec![];
+        let mut problems = vec![];
+
+        for (name, config) in &rpm_metadata.targets {
+            let key = config_key("targets", name);
+            let extra_paths_key = format!("{}.extra_paths", key);
+            let problem_count = problems.len();
+
+            check_install_path(&config.path, &key, &mut problems);
+
+            for path in config.extra_paths.iter().flatten() {
+                check_install_path(path, &extra_paths_key, &mut problems);
+            }
+
+            if problems.len() > problem_count {
+                continue;
+            }
+
+            let src_path = match (&config.source, &config.bin) {
+                (Some(source), _) => {
+                    let (dir, path) = sources.resolve(source)?;
+                    dir.join(path)
+                }
+                (None, Some(bin)) => sources.target.join(bin),
+                (None, None) => sources.target.join(name),
+            };
+
+            archive_files.push(ArchiveFile::new(
+                &src_path,
+                &base_dir,
+                config,
+                &key,
+                DEFAULT_TARGET_MODE,
+            )?);
+
+            // Additional install paths end up as hardlinks to the first
+            for path in config.extra_paths.iter().flatten() {
+                let mut config = config.clone();
+                config.path = path.clone();
+
+                archive_files.push(ArchiveFile::new(
+                    &src_path,
                     &base_dir,
-                    config,
+                    &config,
+                    &extra_paths_key,
                     DEFAULT_TARGET_MODE,
-                )
-                .unwrap()
-            })
-            .collect();
+                )?);
+            }
+        }
+
+        let targets = archive_files.len();
 
         if let Some(ref extra_files) = rpm_metadata.files {
-            let mut queue = std::collections::VecDeque::new();
+            let global_excludes = compile_patterns(rpm_metadata.exclude.as_ref())?;
+            let mut entries = vec![];
+            let mut queue = VecDeque::new();
 
+            // Each queued item is the source path, its config, the index of
+            // its entry's key, source directory and exclusion patterns, and
+            // whether it was found by a glob or by recursing into a directory
+            // (and is therefore excludable)
             for (name, config) in extra_files {
-                queue.push_back((rpm_config_dir.join(name), config.clone()));
+                let key = config_key("files", name);
+
+                if !check_install_path(&config.path, &key, &mut problems) {
+                    continue;
+                }
+
+                let (source_dir, name) = match config.source {
+                    Some(ref source) => sources.resolve(source)?,
+                    None => (sources.config.clone(), name.clone()),
+                };
+
+                let mut entry_excludes = compile_patterns(config.exclude.as_ref())?;
+                entry_excludes.extend(global_excludes.iter().cloned());
+                entries.push((key, source_dir.clone(), entry_excludes));
+                let index = entries.len() - 1;
+
+                if !is_glob(&name) {
+                    queue.push_back((source_dir.join(&name), config.clone(), index, false));
+                    continue;
+                }
+
+                // Globs install their matches below `path`, relative to the
+                // pattern's literal leading directories
+                let prefix = literal_prefix(&name);
+                let matches = expand_glob(&source_dir, &name)?;
+
+                if matches.is_empty() {
+                    status_warn!("no files in {} match {:?}", source_dir.display(), name);
+                }
+
+                for relative in matches {
+                    let mut config = config.clone();
+                    config.path.push(relative.strip_prefix(&prefix)?);
+                    queue.push_back((source_dir.join(&relative), config, index, true));
+                }
             }
 
-            while let Some((path, config)) = queue.pop_front() {
-                if path.is_dir() {
-                    for sub in path.read_dir()? {
+            while let Some((path, mut config, index, discovered)) = queue.pop_front() {
+                let (ref key, ref source_dir, ref excludes) = entries[index];
+                let is_excluded = |path: &Path| {
+                    path.strip_prefix(source_dir)
+                        .map(|relative| is_excluded(excludes, relative))
+                        .unwrap_or(false)
+                };
+
+                if discovered && is_excluded(&path) {
+                    continue;
+                }
+
+                // Preserve symlinks in the config directory rather than
+                // packaging the files they point to
+                if config.link_target.is_none() && is_symlink(&path) {
+                    config.link_target = Some(fs::read_link(&path)?);
+                }
+
+                if config.link_target.is_none()
+                    && config.file_type != Some(FileType::Dir)
+                    && path.is_dir()
+                {
+                    let mut entries = vec![];
+
+                    for entry in path.read_dir()? {
+                        let entry = entry?;
+
+                        if !is_excluded(&entry.path()) {
+                            entries.push(entry);
+                        }
+                    }
+
+                    // Keep empty directories, which the package will own
+                    if entries.is_empty() {
+                        config.file_type = Some(FileType::Dir);
+                        config.mode = None;
+                        queue.push_back((path, config, index, false));
+                        continue;
+                    }
+
+                    for entry in entries {
                         let mut config = config.clone();
-                        let sub = sub?;
-                        config.path.push(sub.file_name());
-                        queue.push_back((sub.path(), config));
+                        config.path.push(entry.file_name());
+                        queue.push_back((entry.path(), config, index, true));
                     }
                 } else {
+                    // Unless configured, extra files keep the permissions of
+                    // their source (e.g. executable scripts), minus the umask
+                    let default_mode = match source_mode(&path) {
+                        Some(mode) => mode & !umask,
+                        None if config.file_type == Some(FileType::Dir) => DEFAULT_DIR_MODE,
+                        None => DEFAULT_FILE_MODE,
+                    };
+
                     archive_files.push(ArchiveFile::new(
                         &path,
                         &base_dir,
                         &config,
-                        DEFAULT_FILE_MODE,
+                        key,
+                        default_mode,
                     )?);
                 }
             }
         }
 
+        for file in generated {
+            if !check_install_path(&file.install_path, &file.config_key, &mut problems) {
+                continue;
+            }
+
+            let config = FileConfig {
+                path: file.install_path.clone(),
+                ..FileConfig::default()
+            };
+
+            let mut archive_file = ArchiveFile::new(
+                &file.src_path,
+                &base_dir,
+                &config,
+                &file.config_key,
+                file.mode,
+            )?;
+
+            archive_file.noreplace = file.noreplace;
+            archive_files.push(archive_file);
+        }
+
+        check_layout(&archive_files, &mut problems);
+
+        if !problems.is_empty() {
+            fail!(
+                ErrorKind::Config,
+                "invalid package layout in Cargo.toml:\n  - {}",
+                problems.join("\n  - ")
+            );
+        }
+
+        link_identical_files(&mut archive_files);
+
         Ok(Self {
             files: archive_files,
+            targets,
+            generated: generated.len(),
         })
     }
 
-    /// Build the archive, placing the resulting file at the given path
-    pub fn build(&self, output_file: &Path) -> Result<(), Error> {
+    /// Spec `%files` entries for the target binaries
+    pub fn target_entries(&self) -> Vec<String> {
+        self.files[..self.targets]
+            .iter()
+            .map(ArchiveFile::files_entry)
+            .collect()
+    }
+
+    /// Spec `%files` entries for the generated files
+    pub fn generated_entries(&self) -> Vec<String> {
+        self.files[self.files.len() - self.generated..]
+            .iter()
+            .map(ArchiveFile::files_entry)
+            .collect()
+    }
+
+    /// Spec `%files` entries for the directories owned by the package
+    pub fn dir_entries(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|file| file.is_dir())
+            .map(ArchiveFile::files_entry)
+            .collect()
+    }
+
+    /// Add every file in the archive to a fingerprint, hashing the contents
+    /// of regular files on the given number of threads
+    pub fn fingerprint(&self, hasher: &mut FingerprintHasher, threads: u32) -> Result<(), Error> {
+        let is_file = |file: &&ArchiveFile| matches!(file.kind, EntryKind::File);
+        let paths = self
+            .files
+            .iter()
+            .filter(is_file)
+            .map(|file| file.src_path.clone())
+            .collect();
+
+        let mut digests = fingerprint::digest_files(paths, threads)?.into_iter();
+
+        for file in &self.files {
+            let content_digest = if is_file(&file) { digests.next() } else { None };
+
+            file.fingerprint(hasher, content_digest);
+        }
+
+        Ok(())
+    }
+
+    /// Build the archive, placing the resulting file at the given path and
+    /// compressing it with the given compression (and number of threads)
+    pub fn build(
+        &self,
+        output_file: &Path,
+        compression: Compression,
+        threads: Option<u32>,
+    ) -> Result<(), Error> {
         let archive = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .open(output_file)?;
 
-        let gzipper = GzEncoder::new(archive, Compression::default());
-        let mut builder = Builder::new(gzipper);
+        let mut builder = Builder::new(compression.encoder(archive, threads)?);
 
         for file in &self.files {
             file.append_to(&mut builder)?;
         }
 
+        builder.into_inner()?.finish()?;
         Ok(())
     }
 }
+
+/// Parse an octal mode string such as `"644"` or `"0755"`, describing what
+/// it is the mode of in the error if it is invalid
+pub fn parse_mode(mode: &str, name: &dyn fmt::Display) -> Result<u32, Error> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(bits) if bits <= 0o7777 && !mode.starts_with('+') => Ok(bits),
+        _ => fail!(
+            ErrorKind::Config,
+            "invalid mode {:?} for {}: expected an octal number such as \"644\" or \"0755\"",
+            mode,
+            name
+        ),
+    }
+}
+
+/// Full Cargo.toml key of an entry in the given table of the RPM metadata,
+/// e.g. `package.metadata.rpm.files."foo.conf"`
+pub fn config_key(table: &str, name: &str) -> String {
+    let is_bare = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if is_bare {
+        format!("package.metadata.rpm.{}.{}", table, name)
+    } else {
+        format!("package.metadata.rpm.{}.{:?}", table, name)
+    }
+}
+
+/// Check that an install path is absolute, normalized and not somewhere
+/// packages shouldn't install to, adding a problem (attributed to the given
+/// Cargo.toml key) if it isn't. Returns whether the path is valid.
+fn check_install_path(path: &Path, key: &str, problems: &mut Vec<String>) -> bool {
+    let normalized: PathBuf = path.components().collect();
+
+    let problem = if !path.is_absolute() {
+        format!("path {:?} is not absolute", path)
+    } else if path.components().any(|c| c == Component::ParentDir) {
+        format!("path {:?} contains a `..` component", path)
+    } else if normalized.as_os_str() != path.as_os_str() {
+        format!(
+            "path {:?} is not normalized (expected {:?})",
+            path, normalized
+        )
+    } else if path == Path::new("/") {
+        "can't install to `/` itself".to_owned()
+    } else if let Some((dir, purpose)) = RESERVED_DIRS.iter().find(|(dir, _)| path.starts_with(dir))
+    {
+        format!(
+            "path {:?} is within {}, which is reserved for {}",
+            path, dir, purpose
+        )
+    } else {
+        return true;
+    };
+
+    problems.push(format!("{}: {}", key, problem));
+    false
+}
+
+/// Check that no two files are installed at the same path, and that nothing
+/// is installed inside a path which is a file (or symlink) rather than a
+/// directory
+fn check_layout(files: &[ArchiveFile], problems: &mut Vec<String>) {
+    let mut installed: HashMap<&Path, &ArchiveFile> = HashMap::new();
+
+    for file in files {
+        match installed.get(file.install_path.as_path()) {
+            Some(other) => problems.push(format!(
+                "{}: {} is also installed by {}",
+                file.config_key,
+                file.install_path.display(),
+                other.config_key
+            )),
+            None => {
+                installed.insert(&file.install_path, file);
+            }
+        }
+    }
+
+    for file in files {
+        for ancestor in file.install_path.ancestors().skip(1) {
+            let other = match installed.get(ancestor) {
+                Some(other) if !other.is_dir() => other,
+                _ => continue,
+            };
+
+            let problem = format!(
+                "{}: installs files inside {}, which {} installs as a {}",
+                file.config_key,
+                ancestor.display(),
+                other.config_key,
+                match other.kind {
+                    EntryKind::Symlink(_) => "symlink",
+                    _ => "file",
+                }
+            );
+
+            if !problems.contains(&problem) {
+                problems.push(problem);
+            }
+        }
+    }
+}
+
+/// Does the given string contain any glob metacharacters?
+fn is_glob(s: &str) -> bool {
+    s.contains(GLOB_META_CHARS)
+}
+
+/// Compile a glob pattern
+fn compile_pattern(pattern: &str) -> Result<Pattern, Error> {
+    Ok(Pattern::new(pattern).map_err(|e| {
+        format_err!(
+            ErrorKind::Config,
+            "invalid glob pattern {:?}: {}",
+            pattern,
+            e.msg
+        )
+    })?)
+}
+
+/// Compile a list of exclusion patterns
+fn compile_patterns(patterns: Option<&Vec<String>>) -> Result<Vec<Pattern>, Error> {
+    patterns
+        .map(|patterns| patterns.iter().map(|p| compile_pattern(p)).collect())
+        .unwrap_or_else(|| Ok(vec![]))
+}
+
+/// Leading components of a glob pattern which contain no metacharacters
+fn literal_prefix(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+/// Find the paths (relative to `source_dir`) of all files and directories
+/// matching a glob pattern, in sorted order. Matches inside a matching
+/// directory are left out, since the directory's contents are installed
+/// along with it.
+fn expand_glob(source_dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    // Check the pattern on its own, so errors don't mention `source_dir`
+    compile_pattern(pattern)?;
+
+    // A trailing `**` only yields directories, so match what it's in instead
+    // (whose contents are installed along with it)
+    let mut pattern = pattern.trim_end_matches('/');
+    while pattern.ends_with("/**") {
+        pattern = &pattern[..pattern.len() - 3];
+    }
+    if pattern == "**" {
+        pattern = "*";
+    }
+
+    let full_pattern = Path::new(&Pattern::escape(&source_dir.to_string_lossy())).join(pattern);
+    let paths = glob::glob_with(&full_pattern.to_string_lossy(), GLOB_OPTIONS).map_err(|e| {
+        format_err!(
+            ErrorKind::Config,
+            "invalid glob pattern {:?}: {}",
+            full_pattern,
+            e.msg
+        )
+    })?;
+    // `glob` leaves out a leading `./` from the paths it yields
+    let base: PathBuf = source_dir
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    let mut matches = vec![];
+
+    for path in paths {
+        let path = path.map_err(io::Error::from)?;
+        let relative = path.strip_prefix(&base)?;
+
+        if relative.components().next().is_some() {
+            matches.push(relative.to_owned());
+        }
+    }
+
+    // Sorting puts directories right before their contents
+    matches.sort();
+    let mut expanded: Vec<PathBuf> = vec![];
+
+    for path in matches {
+        match expanded.last() {
+            Some(dir) if path.starts_with(dir) => (),
+            _ => expanded.push(path),
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Is the given path (relative to the config directory) excluded? Patterns
+/// without a `/` are matched against the file name alone.
+fn is_excluded(patterns: &[Pattern], relative: &Path) -> bool {
+    patterns.iter().any(|pattern| {
+        if pattern.as_str().contains('/') {
+            // `dir/**` only matches `dir/`, but should exclude `dir` too
+            pattern.matches_path_with(relative, GLOB_OPTIONS)
+                || pattern.matches_path_with(&relative.join(""), GLOB_OPTIONS)
+        } else {
+            relative
+                .file_name()
+                .map(|name| pattern.matches_path_with(Path::new(name), GLOB_OPTIONS))
+                .unwrap_or(false)
+        }
+    })
+}
+
+/// Is the given path a symbolic link?
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata()
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Turn files which share an inode with an earlier file in the archive (and
+/// have the same ownership and mode) into hardlinks to it, so multi-call
+/// binaries and the like aren't duplicated in the payload
+fn link_identical_files(files: &mut [ArchiveFile]) {
+    let mut seen: HashMap<(u64, u64), usize> = HashMap::new();
+
+    for i in 0..files.len() {
+        let inode = match files[i].kind {
+            EntryKind::File => match inode(&files[i].src_path) {
+                Some(inode) => inode,
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        let first = *seen.entry(inode).or_insert(i);
+        let (original, file) = (&files[first], &files[i]);
+
+        if first != i
+            && original.username == file.username
+            && original.groupname == file.groupname
+            && original.mode == file.mode
+        {
+            files[i].kind = EntryKind::Hardlink(original.archive_path.clone());
+        }
+    }
+}
+
+/// Permission bits of the file at the given path (if it can be determined)
+#[cfg(unix)]
+fn source_mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| metadata.permissions().mode() & 0o7777)
+}
+
+/// Permission bits of the file at the given path (if it can be determined)
+#[cfg(not(unix))]
+fn source_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+/// Device and inode number of a regular file (if it can be determined)
+#[cfg(unix)]
+fn inode(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path).ok()?;
+
+    if metadata.is_file() {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+/// Device and inode number of a regular file (if it can be determined)
+#[cfg(not(unix))]
+fn inode(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<Pattern> {
+        patterns
+            .iter()
+            .map(|p| compile_pattern(p).unwrap())
+            .collect()
+    }
+
+    /// Create a fresh directory containing the given (empty) files
+    fn tree(name: &str, files: &[&str]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-rpm-glob-{}-{}", name, std::process::id()));
+
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        dir
+    }
+
+    fn expand(pattern: &str, dir: &Path) -> Vec<String> {
+        expand_glob(dir, pattern)
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        compile_pattern(pattern)
+            .unwrap()
+            .matches_path_with(Path::new(path), GLOB_OPTIONS)
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(matches("*.txt", "a.txt"));
+        assert!(!matches("*", ".hidden"));
+        assert!(matches(".*", ".hidden"));
+        assert!(!matches("*.txt", "dir/a.txt"));
+        assert!(!matches("a?c", "a/c"));
+        assert!(matches("[]ab]*", "]z"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(matches("**/*.txt", "x/y/a.txt"));
+        assert!(compile_pattern("[ab").is_err());
+        assert!(compile_pattern("dir/a**").is_err());
+
+        // Backtracking over many `*`s stays fast
+        let name = format!("{}c", "a".repeat(64));
+        assert!(!matches("a*a*a*a*a*a*a*a*a*a*b", &name));
+    }
+
+    #[test]
+    fn literal_prefixes() {
+        assert_eq!(literal_prefix("conf.d/*.toml"), Path::new("conf.d"));
+        assert_eq!(literal_prefix("a/b/**/*.json"), Path::new("a/b"));
+        assert_eq!(literal_prefix("*.conf"), Path::new(""));
+    }
+
+    #[test]
+    fn expand_files() {
+        let dir = tree("files", &["a.txt", "b.txt", "c.md", ".d.txt", "sub/e.txt"]);
+        assert_eq!(expand("*.txt", &dir), ["a.txt", "b.txt"]);
+        assert_eq!(expand("?.md", &dir), ["c.md"]);
+        assert_eq!(expand("**/*.txt", &dir), ["a.txt", "b.txt", "sub/e.txt"]);
+        assert!(expand("*.rs", &dir).is_empty());
+        assert!(expand("missing/**", &dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expand_directory_contents_once() {
+        let dir = tree("dir", &["assets/a.txt", "assets/sub/x.txt", "other/y.txt"]);
+
+        // The directory's contents are installed along with it
+        assert_eq!(expand("assets/**", &dir), ["assets"]);
+        assert_eq!(expand("**", &dir), ["assets", "other"]);
+        assert_eq!(expand("assets/*", &dir), ["assets/a.txt", "assets/sub"]);
+        assert_eq!(
+            expand("**/*.txt", &dir),
+            ["assets/a.txt", "assets/sub/x.txt", "other/y.txt"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn excludes() {
+        let excludes = patterns(&["*.orig", "docs/**", "build/tmp"]);
+        assert!(is_excluded(&excludes, Path::new("a.orig")));
+        assert!(is_excluded(&excludes, Path::new("deep/dir/a.orig")));
+        assert!(is_excluded(&excludes, Path::new("docs")));
+        assert!(is_excluded(&excludes, Path::new("docs/x/y.md")));
+        assert!(is_excluded(&excludes, Path::new("build/tmp")));
+        assert!(!is_excluded(&excludes, Path::new("other/build/tmp")));
+        assert!(!is_excluded(&excludes, Path::new("a.conf")));
+        assert!(!is_excluded(&[], Path::new("a.orig")));
+    }
+
+    fn install_path_problem(path: &str) -> Option<String> {
+        let mut problems = vec![];
+
+        if check_install_path(Path::new(path), "files.x", &mut problems) {
+            assert!(problems.is_empty());
+            None
+        } else {
+            assert_eq!(problems.len(), 1);
+            problems.pop()
+        }
+    }
+
+    #[test]
+    fn install_paths() {
+        assert_eq!(install_path_problem("/usr/bin/foo"), None);
+        assert_eq!(install_path_problem("/etc/foo/foo.toml"), None);
+        assert_eq!(install_path_problem("/runtime/foo"), None);
+
+        for (path, problem) in &[
+            ("usr/bin/foo", "is not absolute"),
+            ("/usr/bin/../sbin/foo", "contains a `..` component"),
+            ("/usr//bin/foo", "is not normalized"),
+            ("/usr/./bin/foo", "is not normalized"),
+            ("/usr/bin/", "is not normalized"),
+            ("/", "can't install to `/` itself"),
+            ("/run", "reserved for runtime state"),
+            ("/run/foo/foo.pid", "reserved for runtime state"),
+            ("/tmp/foo", "reserved for temporary files"),
+            ("/usr/lib/debug/foo", "reserved for debuginfo packages"),
+        ] {
+            let message = install_path_problem(path).unwrap();
+            assert!(message.starts_with("files.x: "), "{}", message);
+            assert!(message.contains(problem), "{}: {}", path, message);
+        }
+    }
+
+    fn file(config_key: &str, install_path: &str, kind: EntryKind) -> ArchiveFile {
+        ArchiveFile {
+            src_path: PathBuf::new(),
+            archive_path: PathBuf::new(),
+            install_path: PathBuf::from(install_path),
+            kind,
+            config_key: config_key.to_owned(),
+            username: DEFAULT_USERNAME.to_owned(),
+            groupname: DEFAULT_GROUPNAME.to_owned(),
+            mode: 0o644,
+            noreplace: false,
+        }
+    }
+
+    #[test]
+    fn files_entries() {
+        let mut config = file("files.conf", "/etc/foo/foo.conf", EntryKind::File);
+        config.noreplace = true;
+        config.groupname = "foo".to_owned();
+
+        assert_eq!(
+            file("targets.foo", "/usr/bin/foo", EntryKind::File).files_entry(),
+            "%attr(0644,root,root) /usr/bin/foo"
+        );
+        assert_eq!(
+            config.files_entry(),
+            "%config(noreplace) %attr(0644,root,foo) /etc/foo/foo.conf"
+        );
+        assert_eq!(
+            file("files.dir", "/var/lib/foo", EntryKind::Dir).files_entry(),
+            "%dir %attr(0644,root,root) /var/lib/foo"
+        );
+        assert_eq!(
+            file(
+                "files.link",
+                "/usr/bin/bar",
+                EntryKind::Symlink(PathBuf::from("foo"))
+            )
+            .files_entry(),
+            "%attr(-,root,root) /usr/bin/bar"
+        );
+    }
+
+    fn layout_problems(files: &[ArchiveFile]) -> Vec<String> {
+        let mut problems = vec![];
+        check_layout(files, &mut problems);
+        problems
+    }
+
+    #[test]
+    fn layout() {
+        let files = [
+            file("targets.foo", "/usr/bin/foo", EntryKind::File),
+            file("files.dir", "/var/lib/foo", EntryKind::Dir),
+            file("files.state", "/var/lib/foo/state", EntryKind::File),
+        ];
+        assert!(layout_problems(&files).is_empty());
+
+        let files = [
+            file("targets.foo", "/usr/bin/foo", EntryKind::File),
+            file("files.foo", "/usr/bin/foo", EntryKind::File),
+        ];
+        assert_eq!(
+            layout_problems(&files),
+            ["files.foo: /usr/bin/foo is also installed by targets.foo"]
+        );
+
+        let files = [
+            file("files.a", "/etc/foo", EntryKind::File),
+            file("files.b", "/etc/foo/a.conf", EntryKind::File),
+            file("files.c", "/etc/foo/b/c.conf", EntryKind::File),
+        ];
+        assert_eq!(
+            layout_problems(&files),
+            [
+                "files.b: installs files inside /etc/foo, which files.a installs as a file",
+                "files.c: installs files inside /etc/foo, which files.a installs as a file",
+            ]
+        );
+
+        let files = [
+            file(
+                "files.link",
+                "/opt/foo",
+                EntryKind::Symlink(PathBuf::from("/opt/foo-1.0")),
+            ),
+            file("targets.foo", "/opt/foo/bin/foo", EntryKind::File),
+        ];
+        assert_eq!(
+            layout_problems(&files),
+            ["targets.foo: installs files inside /opt/foo, which files.link installs as a symlink"]
+        );
+    }
+}
@@ -1,20 +1,26 @@
 //! RPM builder
 
 use crate::{
-    archive::Archive,
+    archive::{config_key, Archive, GeneratedFile, SourceDirs},
+    compression::Codec,
     config::{PackageConfig, RpmConfig},
-    error::Error,
+    dist::Dist,
+    error::{Error, ErrorKind},
+    fingerprint::{Fingerprint, FingerprintHasher, FINGERPRINT_FILE},
     prelude::*,
     rpmbuild::Rpmbuild,
-    target_architecture::TargetArch,
+    scriptlets::Scriptlets,
+    snippets, systemd, target,
+    target_architecture::{TargetArch, NOARCH},
+    users,
 };
 use std::{
-    env,
+    collections::BTreeMap,
     fs::{self, File, OpenOptions},
-    io::{Read, Write},
+    io::{BufReader, Read, Write},
     path::{Path, PathBuf},
     process::{self, Command, Stdio},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 /// Default build profile to use
@@ -29,6 +35,144 @@ pub const VERSION_PLACEHOLDER: &str = "@@VERSION@@";
 /// Placeholder string in the `.spec` file we use for the release
 pub const RELEASE_PLACEHOLDER: &str = "@@RELEASE@@";
 
+/// Placeholder string in the `.spec` file we use for the name of the release
+/// archive, whose extension depends on its compression
+pub const SOURCE_PLACEHOLDER: &str = "@@SOURCE@@";
+
+/// Name of the release archive in specs generated before `@@SOURCE@@`
+const LEGACY_SOURCE: &str = "%{name}-%{version}.tar.gz";
+
+/// Placeholder string in the `%files` section of the `.spec` file we use for
+/// the installed target binaries
+pub const TARGETS_PLACEHOLDER: &str = "@@TARGETS@@";
+
+/// Placeholder string in the `%files` section of the `.spec` file we use for
+/// directories owned by the package
+pub const DIRS_PLACEHOLDER: &str = "@@DIRS@@";
+
+/// Placeholder string in the `%files` section of the `.spec` file we use for
+/// files generated from `Cargo.toml` (e.g. systemd units)
+pub const GENERATED_FILES_PLACEHOLDER: &str = "@@GENERATED_FILES@@";
+
+/// Placeholder string in the preamble of the `.spec` file we use for the
+/// dependencies of generated files' scriptlets
+pub const REQUIRES_PLACEHOLDER: &str = "@@REQUIRES@@";
+
+/// Placeholder string in the `.spec` file we use for the scriptlets of
+/// generated files
+pub const SCRIPTLETS_PLACEHOLDER: &str = "@@SCRIPTLETS@@";
+
+/// Subdirectory of the rpmbuild directory where generated files are written
+pub const GENERATED_DIR: &str = "generated";
+
+/// Compute the list of Rust targets to build RPMs for.
+///
+/// Targets given on the command line take precedence over the `target` and
+/// `targets` keys of `[package.metadata.rpm.cargo]`. If no targets are
+/// configured anywhere, a single build for the host is performed. The
+/// `noarch` pseudo-target builds an architecture-independent package.
+pub fn build_targets(
+    config: &PackageConfig,
+    targets: &[String],
+) -> Result<Vec<Option<String>>, Error> {
+    let mut config_targets = vec![];
+
+    if let Some(cargo) = config.rpm_metadata().and_then(|rpm| rpm.cargo.as_ref()) {
+        if let Some(ref t) = cargo.target {
+            config_targets.push(t.to_owned());
+        }
+
+        if let Some(ref ts) = cargo.targets {
+            config_targets.extend(ts.iter().cloned());
+        }
+    }
+
+    let requested = if !targets.is_empty() {
+        if !config_targets.is_empty() {
+            status_warn!("target also specified as part of [package.metadata.rpm.cargo] in Cargo.toml, but ignoring it");
+        }
+
+        targets.to_vec()
+    } else {
+        config_targets
+    };
+
+    // An explicit `target_architecture` overrides the one derived from the target
+    let arch_override = config
+        .rpm_metadata()
+        .and_then(|rpm| rpm.target_architecture.as_ref())
+        .is_some();
+
+    let mut result: Vec<String> = vec![];
+
+    for target in requested {
+        // Make sure every target maps to an RPM architecture before building
+        // anything. `target_architecture` only stands in for the arch name,
+        // so the target still has to be one RPMs can be built for.
+        if arch_override {
+            TargetArch::check_rust_target(&target)?;
+        } else {
+            TargetArch::parse(&target)?;
+        }
+
+        if !result.contains(&target) {
+            result.push(target);
+        }
+    }
+
+    if result.is_empty() {
+        Ok(vec![None])
+    } else {
+        Ok(result.into_iter().map(Some).collect())
+    }
+}
+
+/// Run a build stage inside of a tracing span, recording how long it took
+fn time_stage<T, F>(
+    stages: &mut Vec<(&'static str, Duration)>,
+    name: &'static str,
+    stage: F,
+) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    let span = span!(Level::INFO, "stage", name);
+    let _enter = span.enter();
+
+    let began_at = Instant::now();
+    let result = stage()?;
+    let elapsed = began_at.elapsed();
+
+    debug!(elapsed_ms = elapsed.as_millis() as u64, "stage finished");
+    stages.push((name, elapsed));
+    Ok(result)
+}
+
+/// Information about an RPM produced by the builder
+#[derive(Clone, Debug)]
+pub struct BuiltRpm {
+    /// Rust target the RPM was built for (or `None` for the host)
+    pub target: Option<String>,
+
+    /// RPM target architecture passed to `rpmbuild`
+    pub arch: String,
+
+    /// Name, version, release and architecture of the resulting RPM
+    pub nevra: String,
+
+    /// Path to the binary RPM (unless only a source RPM was built)
+    pub rpm: Option<PathBuf>,
+
+    /// Path to the source RPM (if one was built)
+    pub srpm: Option<PathBuf>,
+
+    /// Time taken by each stage of the build
+    pub stages: Vec<(&'static str, Duration)>,
+
+    /// Time taken to build the RPM
+    pub elapsed: Duration,
+}
+
 /// Build RPMs from Rust projects
 pub struct Builder {
     /// Cargo.toml configuration
@@ -40,6 +184,12 @@ pub struct Builder {
     /// Can we assume that the project is already built?
     pub no_cargo_build: bool,
 
+    /// Rebuild the RPM even if its inputs are unchanged?
+    pub force: bool,
+
+    /// Distribution to build for (overriding `default_dist` in Cargo.toml)
+    pub dist: Option<Dist>,
+
     /// Rust target for cross-compilation
     pub target: Option<String>,
 
@@ -86,9 +236,6 @@ impl Builder {
             }
         }
 
-        if target.is_some() && config_target.is_some() {
-            status_warn!("target also specified as part of [package.metadata.rpm.cargo] in Cargo.toml, but ignoring it");
-        }
         let final_target = target.or(config_target);
 
         let target_dir = base_target_dir
@@ -100,6 +247,8 @@ impl Builder {
             config: config.clone(),
             verbose,
             no_cargo_build,
+            force: false,
+            dist: None,
             target: final_target.cloned(),
             output_path: output_path.cloned(),
             rpm_config_dir: rpm_config_dir.into(),
@@ -109,29 +258,153 @@ impl Builder {
     }
 
     /// Build an RPM for this package
-    pub fn build(&self) -> Result<(), Error> {
+    pub fn build(mut self) -> Result<BuiltRpm, Error> {
+        let span = span!(
+            Level::INFO,
+            "build",
+            target = self.target.as_deref().unwrap_or("host")
+        );
+        let _enter = span.enter();
+
         let began_at = Instant::now();
+        let arch = self.rpm_target_architecture()?;
+        let mut stages = vec![];
+
+        // Apply the targets and files for this architecture and distribution
+        self.config = self.config.with_overrides(&arch, self.dist())?;
 
-        if !self.no_cargo_build {
-            self.cargo_build()?;
+        let mut sources = SourceDirs {
+            crate_root: PathBuf::from("."),
+            config: self.rpm_config_dir.clone(),
+            target: self.target_dir.clone(),
+            out_dirs: BTreeMap::new(),
+        };
+
+        // noarch packages are data-only, so there's nothing for cargo to build
+        if arch == NOARCH {
+            if let Some(name) = self.rpm_metadata().targets.keys().next() {
+                fail!(
+                    ErrorKind::Config,
+                    "{}: noarch packages can't contain compiled binaries \
+                     (remove the target or build for an architecture)",
+                    config_key("targets", name)
+                );
+            }
+        } else if !self.no_cargo_build {
+            sources.out_dirs = time_stage(&mut stages, "cargo_build", || self.cargo_build())?;
         }
-        self.build_hooks()?;
-        self.create_archive()?;
-        self.render_spec()?;
-        self.rpmbuild()?;
+        time_stage(&mut stages, "build_hooks", || self.build_hooks())?;
+        let mut scriptlets = Scriptlets::default();
+        let generated = time_stage(&mut stages, "generate", || {
+            self.generate_files(&mut scriptlets)
+        })?;
+        let archive = Archive::new(&self.config, &sources, &generated)?;
+        let spec = time_stage(&mut stages, "render_spec", || {
+            self.render_spec(&archive, &scriptlets)
+        })?;
+        let digest = time_stage(&mut stages, "fingerprint", || {
+            self.fingerprint(&archive, &arch, &spec)
+        })?;
+
+        // Reuse the previous RPM if none of its inputs have changed
+        let fingerprint_path = self.rpmbuild_dir.join(FINGERPRINT_FILE);
+        let previous = Fingerprint::load(&fingerprint_path)
+            .filter(|fingerprint| !self.force && fingerprint.is_fresh(&digest));
+
+        let written = match previous {
+            Some(fingerprint) => {
+                status_ok!(
+                    "Fresh",
+                    "inputs unchanged; reusing previous RPM (use --force to rebuild)"
+                );
+                fingerprint.rpms
+            }
+            None => {
+                // Don't leave a stale fingerprint behind if the build fails
+                if fingerprint_path.exists() {
+                    fs::remove_file(&fingerprint_path)?;
+                }
 
-        let (version, release) = self.config.version();
+                time_stage(&mut stages, "create_archive", || {
+                    self.create_archive(&archive)
+                })?;
+                let written = time_stage(&mut stages, "rpmbuild", || self.rpmbuild(&arch))?;
+
+                Fingerprint {
+                    digest,
+                    rpms: written.clone(),
+                }
+                .save(&fingerprint_path)?;
+
+                written
+            }
+        };
+
+        let (srpm, rpm): (Vec<PathBuf>, Vec<PathBuf>) = written
+            .into_iter()
+            .partition(|path| path.to_string_lossy().ends_with(".src.rpm"));
+
+        let rpm = rpm.into_iter().next();
+        let srpm = srpm.into_iter().next();
+
+        let (primary, primary_arch) = match (&rpm, &srpm) {
+            (Some(path), _) => (path, arch.as_str()),
+            (None, Some(path)) => (path, "src"),
+            (None, None) => fail!(ErrorKind::Rpmbuild, "no RPM found in rpmbuild output"),
+        };
+
+        let nevra = self.nevra(primary_arch, primary)?;
+        let elapsed = began_at.elapsed();
 
         status_ok!(
             "Finished",
-            "{}-{}-{}.rpm: built in {} secs",
-            self.config.rpm_name(),
-            version,
-            release,
-            began_at.elapsed().as_secs()
+            "{}: built in {} secs",
+            primary.display(),
+            elapsed.as_secs()
         );
 
-        Ok(())
+        if let (Some(_), Some(ref srpm)) = (&rpm, &srpm) {
+            status_ok!("Finished", "{}", srpm.display());
+        }
+
+        if self.verbose {
+            for (name, duration) in &stages {
+                status_info!(
+                    "Timing",
+                    "{:<16} {:>8.2} secs",
+                    name,
+                    duration.as_secs_f64()
+                );
+            }
+        }
+
+        Ok(BuiltRpm {
+            target: self.target.clone(),
+            arch,
+            nevra,
+            rpm,
+            srpm,
+            stages,
+            elapsed,
+        })
+    }
+
+    /// Determine the name-(epoch:)version-release.arch of the built RPM,
+    /// asking rpm for it and falling back on computing it if that fails
+    fn nevra(&self, arch: &str, rpm_path: &Path) -> Result<String, Error> {
+        let cmd = self.rpmbuild_cmd()?;
+
+        match cmd.query_nevra(rpm_path) {
+            Ok(nevra) => return Ok(nevra),
+            Err(e) => {
+                if self.verbose {
+                    status_warn!("{}; deriving the RPM's name from Cargo.toml instead", e);
+                }
+            }
+        }
+
+        // Specs generated by `cargo rpm init` don't set an epoch
+        Ok(format!("{}.{}", self.rpm_nvr(&self.dist_tag(&cmd)?), arch))
     }
 
     /// Retrieve the RPM metadata for this crate
@@ -139,8 +412,14 @@ impl Builder {
         self.config.rpm_metadata().unwrap()
     }
 
-    /// Compile the project with "cargo build"
-    fn cargo_build(&self) -> Result<(), Error> {
+    /// Distribution we're building for (if any)
+    fn dist(&self) -> Option<Dist> {
+        self.dist.or(self.rpm_metadata().default_dist)
+    }
+
+    /// Compile the project with "cargo build", returning the `OUT_DIR` of
+    /// each crate's build script if any files are taken from one
+    fn cargo_build(&self) -> Result<BTreeMap<String, PathBuf>, Error> {
         let mut buildflags = vec![];
 
         if let Some(ref t) = self.target {
@@ -153,21 +432,72 @@ impl Builder {
             }
         };
 
+        // Build script output directories are only reported in cargo's JSON
+        // messages (diagnostics are still rendered to stderr as usual), so
+        // they're requested when any target or file is sourced from one
+        let rpm_metadata = self.rpm_metadata();
+        let uses_out_dirs = rpm_metadata
+            .targets
+            .values()
+            .chain(rpm_metadata.files.iter().flat_map(|files| files.values()))
+            .any(|file| {
+                file.source
+                    .as_ref()
+                    .map(|source| source.starts_with("out_dir:"))
+                    .unwrap_or(false)
+            });
+
+        if uses_out_dirs {
+            if buildflags
+                .iter()
+                .any(|flag| flag.starts_with("--message-format"))
+            {
+                fail!(
+                    ErrorKind::Config,
+                    "{}: --message-format can't be set when sources come from \
+                     out_dir: (cargo-rpm reads cargo's JSON messages to find them)",
+                    config_key("cargo", "buildflags")
+                );
+            }
+
+            buildflags.push("--message-format=json-render-diagnostics".to_owned());
+        }
+
         if self.verbose {
             status_ok!("Running", "cargo build {}", buildflags.join(" "));
         }
 
-        let status = Command::new("cargo")
+        let mut child = Command::new("cargo")
             .arg("build")
             .args(&buildflags)
-            .status()?;
+            .stdout(if uses_out_dirs {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
+            .spawn()?;
+
+        let out_dirs = match child.stdout.take() {
+            Some(stdout) => match target::find_out_dirs(BufReader::new(stdout)) {
+                Ok(out_dirs) => out_dirs,
+                Err(err) => {
+                    // Don't leave cargo running (or a zombie) behind. It may
+                    // have already exited, so failing to kill it is fine.
+                    let _ = child.kill();
+                    child.wait()?;
+                    return Err(err);
+                }
+            },
+            None => BTreeMap::new(),
+        };
+
+        let status = child.wait()?;
 
-        // Exit with the same exit code cargo used
         if !status.success() {
-            process::exit(status.code().unwrap_or(1));
+            fail!(ErrorKind::Build, "cargo build failed ({})", status);
         }
 
-        Ok(())
+        Ok(out_dirs)
     }
 
     /// Launch commands after `cargo build`  
@@ -192,43 +522,150 @@ impl Builder {
                     .status()?;
 
                 if !status.success() {
-                    status_err!(
-                        "Failed to launch build hook \"{}\" `{}`",
+                    fail!(
+                        ErrorKind::Build,
+                        "build hook \"{}\" `{}` failed ({})",
                         cmd,
-                        args.join(" ")
+                        args.join(" "),
+                        status
                     );
-                    process::exit(status.code().unwrap_or(1));
                 }
             }
         }
         Ok(())
     }
 
+    /// Generate files from settings in Cargo.toml, adding the scriptlets they
+    /// need
+    fn generate_files(&self, scriptlets: &mut Scriptlets) -> Result<Vec<GeneratedFile>, Error> {
+        let output_dir = self.rpmbuild_dir.join(GENERATED_DIR);
+
+        // Don't leave files from previous builds lying around
+        if output_dir.exists() {
+            fs::remove_dir_all(&output_dir)?;
+        }
+
+        fs::create_dir_all(&output_dir)?;
+
+        // Users come first, so they exist before any other scriptlet runs
+        let mut generated = users::generate_users(
+            &self.config,
+            &output_dir,
+            &self.rpmbuild_dir.join("SOURCES"),
+            self.dist(),
+            scriptlets,
+        )?;
+
+        // Runtime directories come next, so they exist before services start
+        generated.extend(snippets::generate_snippets(
+            &self.config,
+            &output_dir,
+            self.dist(),
+            scriptlets,
+        )?);
+
+        generated.extend(systemd::generate_units(
+            &self.config,
+            &self.rpm_config_dir,
+            &output_dir,
+            self.dist(),
+            scriptlets,
+        )?);
+
+        Ok(generated)
+    }
+
+    /// Compute a digest of everything that goes into the RPM: the archive
+    /// contents, the rendered spec, the package config and the rpmbuild
+    /// target and output path
+    fn fingerprint(&self, archive: &Archive, arch: &str, spec: &str) -> Result<String, Error> {
+        let mut hasher = FingerprintHasher::new();
+
+        hasher
+            .field(env!("CARGO_PKG_VERSION"))
+            .field(format!("{:?}", self.config))
+            .field(arch)
+            .field(self.dist().map(Dist::as_str).unwrap_or(""))
+            .field(self.target.as_deref().unwrap_or(""))
+            .field(self.output_path.as_deref().unwrap_or(""))
+            .field(spec);
+
+        let threads = self
+            .rpm_metadata()
+            .compression
+            .clone()
+            .unwrap_or_default()
+            .archive_threads();
+
+        archive.fingerprint(&mut hasher, threads)?;
+        Ok(hasher.finish())
+    }
+
     /// Create the archive (i.e. tarball) containing targets and additional files
-    fn create_archive(&self) -> Result<(), Error> {
+    fn create_archive(&self, archive: &Archive) -> Result<(), Error> {
         let sources_dir = self.rpmbuild_dir.join("SOURCES");
         fs::create_dir_all(&sources_dir)?;
 
         let (version, _) = self.config.version();
+        let compression_config = self.rpm_metadata().compression.clone().unwrap_or_default();
+        let compression = compression_config.archive.unwrap_or_default();
 
         // Build a tarball containing the RPM's contents
-        let archive_file = format!("{}-{}.tar.gz", self.config.rpm_name(), version);
+        let archive_file = format!(
+            "{}-{}.{}",
+            self.config.rpm_name(),
+            version,
+            compression.codec.tarball_extension()
+        );
         let archive_path = sources_dir.join(&archive_file);
 
+        if let Some(dist) = self.dist() {
+            if compression.codec == Codec::Zstd && !dist.supports_zstd_sources() {
+                fail!(
+                    ErrorKind::Config,
+                    "{} can't unpack zstd-compressed release archives (requested {})",
+                    dist,
+                    compression
+                );
+            }
+        }
+
         if self.verbose {
-            status_ok!("Creating", "release archive: {}", &archive_file);
+            status_ok!(
+                "Creating",
+                "release archive: {} ({})",
+                &archive_file,
+                compression
+            );
         }
 
-        Archive::new(&self.config, &self.rpm_config_dir, &self.target_dir)?.build(&archive_path)?;
+        archive.build(
+            &archive_path,
+            compression,
+            Some(compression_config.archive_threads()),
+        )?;
 
         Ok(())
     }
 
-    /// Render the package's RPM spec file
-    fn render_spec(&self) -> Result<(), Error> {
-        // Read the spec file from `.rpm`
+    /// Render the package's RPM spec file, returning the rendered spec
+    fn render_spec(&self, archive: &Archive, scriptlets: &Scriptlets) -> Result<String, Error> {
+        // Read the spec file from `.rpm`, preferring one for the distribution
+        // we're building for (e.g. `foo.el8.spec`) if there is one
         let spec_filename = format!("{}.spec", self.config.rpm_name());
-        let mut spec_src = File::open(self.rpm_config_dir.join(&spec_filename))?;
+        let mut spec_src_path = self.rpm_config_dir.join(&spec_filename);
+
+        if let Some(dist) = self.dist() {
+            let dist_spec_path =
+                self.rpm_config_dir
+                    .join(format!("{}.{}.spec", self.config.rpm_name(), dist));
+
+            if dist_spec_path.exists() {
+                spec_src_path = dist_spec_path;
+            }
+        }
+
+        let mut spec_src = File::open(&spec_src_path)?;
         let mut spec_template = String::new();
         spec_src.read_to_string(&mut spec_template)?;
 
@@ -238,7 +675,68 @@ impl Builder {
         let spec_ver_rendered = str::replace(&spec_template, VERSION_PLACEHOLDER, &version);
 
         // Replace `@@RELEASE@@` with the crate's release
-        let spec_rendered = str::replace(&spec_ver_rendered, RELEASE_PLACEHOLDER, &release);
+        let spec_rel_rendered = str::replace(&spec_ver_rendered, RELEASE_PLACEHOLDER, &release);
+
+        // Replace `@@SOURCE@@` with the release archive's name, which older
+        // specs spell out with a `.tar.gz` extension whatever the compression
+        let codec = self
+            .rpm_metadata()
+            .compression
+            .as_ref()
+            .and_then(|compression| compression.archive)
+            .unwrap_or_default()
+            .codec;
+        let source = format!("%{{name}}-%{{version}}.{}", codec.tarball_extension());
+        let spec_source_rendered = spec_rel_rendered
+            .replace(SOURCE_PLACEHOLDER, &source)
+            .replace(LEGACY_SOURCE, &source);
+
+        // Replace `@@TARGETS@@` with entries for the installed target binaries
+        let spec_targets_rendered = str::replace(
+            &spec_source_rendered,
+            TARGETS_PLACEHOLDER,
+            &archive.target_entries().join("\n"),
+        );
+
+        // Replace `@@DIRS@@` with `%dir` entries for the package's directories
+        let dir_entries = archive.dir_entries();
+
+        if !dir_entries.is_empty() && !spec_targets_rendered.contains(DIRS_PLACEHOLDER) {
+            status_warn!(
+                "{} has no {} placeholder in %files; package won't own its directories",
+                spec_filename,
+                DIRS_PLACEHOLDER
+            );
+        }
+
+        let spec_dirs_rendered = str::replace(
+            &spec_targets_rendered,
+            DIRS_PLACEHOLDER,
+            &dir_entries.join("\n"),
+        );
+
+        // Replace `@@GENERATED_FILES@@`, `@@REQUIRES@@` and `@@SCRIPTLETS@@`
+        // with the entries, dependencies and scriptlets of generated files
+        let generated_entries = archive.generated_entries();
+
+        for &(placeholder, needed) in &[
+            (GENERATED_FILES_PLACEHOLDER, !generated_entries.is_empty()),
+            (REQUIRES_PLACEHOLDER, !scriptlets.preamble.is_empty()),
+            (SCRIPTLETS_PLACEHOLDER, scriptlets.has_sections()),
+        ] {
+            if needed && !spec_dirs_rendered.contains(placeholder) {
+                status_warn!(
+                    "{} has no {} placeholder; generated files won't be installed properly",
+                    spec_filename,
+                    placeholder
+                );
+            }
+        }
+
+        let spec_rendered = spec_dirs_rendered
+            .replace(GENERATED_FILES_PLACEHOLDER, &generated_entries.join("\n"))
+            .replace(REQUIRES_PLACEHOLDER, &scriptlets.render_preamble())
+            .replace(SCRIPTLETS_PLACEHOLDER, &scriptlets.render_sections());
 
         let spec_dir = self.rpmbuild_dir.join("SPECS");
         fs::create_dir_all(&spec_dir)?;
@@ -251,7 +749,7 @@ impl Builder {
 
         spec_file.write_all(spec_rendered.as_bytes())?;
 
-        Ok(())
+        Ok(spec_rendered)
     }
 
     /// Interpret the output path string as rpm (dir, filename) pair, when it's present
@@ -280,17 +778,46 @@ impl Builder {
         })
     }
 
-    /// Run rpmbuild
-    fn rpmbuild(&self) -> Result<(), Error> {
+    /// Name-version-release of the RPM, with the given dist tag appended to
+    /// the release as `%{?dist}` is in generated specs
+    fn rpm_nvr(&self, dist_tag: &str) -> String {
         let (version, release) = self.config.version();
-        let rpm_file = format!("{}-{}-{}.rpm", self.config.rpm_name(), version, release);
-        let cmd = Rpmbuild::new(self.verbose)?;
+        format!(
+            "{}-{}-{}{}",
+            self.config.rpm_name(),
+            version,
+            release,
+            dist_tag
+        )
+    }
+
+    /// Dist tag rpmbuild will expand `%{?dist}` to, taking the distribution
+    /// we're building for and user-supplied defines into account
+    fn dist_tag(&self, cmd: &Rpmbuild) -> Result<String, Error> {
+        let mut defines = vec![];
+
+        if let Some(dist_tag) = self.dist().and_then(Dist::dist_tag) {
+            defines.push(format!("dist {}", dist_tag));
+        }
+
+        if let Some(ref rpmbuild_config) = self.rpm_metadata().rpmbuild {
+            defines.extend(rpmbuild_config.defines.iter().flatten().cloned());
+        }
+
+        cmd.eval_with(&defines, "%{?dist}")
+    }
+
+    /// Run rpmbuild
+    fn rpmbuild(&self, arch: &str) -> Result<Vec<PathBuf>, Error> {
+        let cmd = self.rpmbuild_cmd()?;
+        let rpm_file = format!("{}.{}.rpm", self.rpm_nvr(&self.dist_tag(&cmd)?), arch);
+        let capabilities = cmd.capabilities()?;
 
         status_ok!(
             "Building",
             "{} (using rpmbuild {})",
             rpm_file,
-            cmd.version().unwrap()
+            cmd.version()?
         );
 
         // Create directories needed by rpmbuild
@@ -298,49 +825,139 @@ impl Builder {
             fs::create_dir_all(self.rpmbuild_dir.join(dir))?;
         }
 
-        // Change directory to `target/<profile>/rpmbuild`
-        env::set_current_dir(&self.rpmbuild_dir)?;
-
         // Calculate rpmbuild arguments
         let spec_path = format!("SPECS/{}.spec", self.config.rpm_name());
         let topdir_macro = format!("_topdir {}", self.rpmbuild_dir.display());
         let tmppath_macro = format!("_tmppath {}", self.rpmbuild_dir.join("tmp").display());
 
         // Calculate rpmbuild arguments
-        let mut args = vec!["-ba", &spec_path, "-D", &topdir_macro, "-D", &tmppath_macro];
+        let mode = self.rpm_metadata().rpmbuild_mode();
+        let mut args = vec![
+            mode.as_flag(),
+            &spec_path,
+            "-D",
+            &topdir_macro,
+            "-D",
+            &tmppath_macro,
+        ];
 
         // By default, final rpm output path is:
         // %{_topdir}/RPMS/%{ARCH}/%{NAME}-%{VERSION}-%{RELEASE}.%{ARCH}.rpm
+        // and SRPMs are placed in %{_topdir}/SRPMS.
         // Change it when the output path is specified.
         let mut rpmdir_macro = "_rpmdir ".to_owned();
+        let mut srcrpmdir_macro = "_srcrpmdir ".to_owned();
         let mut build_name_fmt_macro = "_build_name_fmt ".to_owned();
         if let Some((dir, filename)) = self.get_rpm_dir_and_filename() {
             rpmdir_macro.push_str(dir);
-            build_name_fmt_macro.push_str(filename);
-            args.extend(&["-D", &rpmdir_macro, "-D", &build_name_fmt_macro]);
+            srcrpmdir_macro.push_str(dir);
+            args.extend(&["-D", &rpmdir_macro, "-D", &srcrpmdir_macro]);
+
+            if capabilities.build_name_fmt {
+                build_name_fmt_macro.push_str(filename);
+                args.extend(&["-D", &build_name_fmt_macro]);
+            } else {
+                status_warn!(
+                    "this rpmbuild doesn't support %_build_name_fmt; using the default RPM filename"
+                );
+            }
         }
 
         // Set the rpm target architecture
-        let mut arch = "".to_owned();
-        if let Some(config_arch) = self
-            .config
-            .metadata
-            .as_ref()
-            .and_then(|metadata| metadata.rpm.as_ref())
-            .and_then(|rpm| rpm.target_architecture.as_ref())
-        {
-            arch = config_arch.to_owned();
+        args.extend(&["--target", arch]);
+
+        // Set the distribution's dist tag (user-supplied defines still win)
+        let mut dist_macro = "dist ".to_owned();
+        if let Some(dist_tag) = self.dist().and_then(Dist::dist_tag) {
+            dist_macro.push_str(dist_tag);
+            args.extend(&["-D", &dist_macro]);
+        }
+
+        // Select the payload compression (user-supplied defines still win)
+        let mut payload_macro = "_binary_payload ".to_owned();
+        if let Some(ref compression_config) = self.rpm_metadata().compression {
+            if let Some(payload) = compression_config.payload {
+                payload.check_payload()?;
+
+                if let Some(dist) = self.dist() {
+                    if payload.codec == Codec::Zstd && !dist.supports_zstd_payload() {
+                        fail!(
+                            ErrorKind::Config,
+                            "{} can't install RPMs with zstd payloads (requested {})",
+                            dist,
+                            payload
+                        );
+                    }
+                }
+
+                if payload.codec == Codec::Zstd && !capabilities.zstd_payload {
+                    fail!(
+                        ErrorKind::Rpmbuild,
+                        "this rpmbuild doesn't support zstd payloads (requested {})",
+                        payload
+                    );
+                }
+
+                payload_macro.push_str(&payload.payload_macro_value(compression_config.threads));
+                args.extend(&["-D", &payload_macro]);
+            }
+        }
+
+        // Add user-supplied macro definitions and arguments
+        if let Some(ref rpmbuild_config) = self.rpm_metadata().rpmbuild {
+            if let Some(ref defines) = rpmbuild_config.defines {
+                for define in defines {
+                    args.extend(&["-D", define.as_str()]);
+                }
+            }
+
+            if let Some(ref extra_args) = rpmbuild_config.args {
+                args.extend(extra_args.iter().map(String::as_str));
+            }
+        }
+
+        if self.verbose {
+            status_ok!("Running", "{} {}", cmd.path.display(), &args.join(" "));
+        }
+
+        // Actually run rpmbuild from within `target/<profile>/rpmbuild`
+        let output = cmd.exec(&self.rpmbuild_dir, &args)?;
+
+        // rpmbuild reports each package it writes as `Wrote: <path>`
+        Ok(output
+            .lines()
+            .filter(|line| line.starts_with("Wrote: "))
+            .map(|line| PathBuf::from(line["Wrote: ".len()..].trim()))
+            .collect())
+    }
+
+    /// Prepare the configured `rpmbuild` command
+    fn rpmbuild_cmd(&self) -> Result<Rpmbuild, Error> {
+        Rpmbuild::new(self.rpm_metadata().rpmbuild_path(), self.verbose)
+    }
+
+    /// Determine the rpm target architecture, either from the
+    /// `target_architecture` key in `[package.metadata.rpm]`, from the
+    /// Rust target we're building for, or from the host. Building for the
+    /// `noarch` pseudo-target always produces a noarch package.
+    pub fn rpm_target_architecture(&self) -> Result<String, Error> {
+        if self.target.as_deref() == Some(NOARCH) {
+            Ok(NOARCH.to_owned())
+        } else if let Some(config_arch) = self.rpm_metadata().target_architecture.as_ref() {
             if self.verbose {
                 status_ok!(
                     "Configuring",
                     "rpm target architecture (based on [package.metadata.rpm] from Cargo.toml): {}",
-                    arch
+                    config_arch
                 );
             }
+
+            Ok(config_arch.to_owned())
         } else if let Some(target) = self.target.as_ref() {
-            arch = TargetArch::parse(target)?
+            let arch = TargetArch::parse(target)?
                 .as_rpm_target_architecture()
                 .to_owned();
+
             if self.verbose {
                 status_ok!(
                     "Configuring",
@@ -348,16 +965,32 @@ impl Builder {
                     arch
                 );
             }
-        };
-        if !arch.is_empty() {
-            args.extend(&["--target", &arch]);
-        }
 
-        if self.verbose {
-            status_ok!("Running", "{} {}", cmd.path.display(), &args.join(" "));
-        }
+            Ok(arch)
+        } else {
+            // Map the host rust target like any other, only asking rpm for the
+            // CPU it builds for (not `%{_arch}`, which is e.g. `i386` on i686
+            // hosts) if the host target isn't one we know
+            let arch = match TargetArch::host() {
+                Ok(host) => host.as_rpm_target_architecture().to_owned(),
+                Err(e) => match self
+                    .rpmbuild_cmd()
+                    .and_then(|cmd| cmd.eval("%{_target_cpu}"))
+                {
+                    Ok(arch) if !arch.is_empty() && !arch.starts_with('%') => arch,
+                    _ => return Err(e),
+                },
+            };
 
-        // Actually run rpmbuild
-        cmd.exec(&args)
+            if self.verbose {
+                status_ok!(
+                    "Configuring",
+                    "rpm target architecture (based on host): {}",
+                    arch
+                );
+            }
+
+            Ok(arch)
+        }
     }
 }
@@ -1,13 +1,23 @@
 //! The `cargo rpm build` subcommand
 
 use crate::{
-    builder::{Builder, RPM_CONFIG_DIR},
+    builder::{self, Builder, BuiltRpm, RPM_CONFIG_DIR},
+    config::RpmConfig,
+    dist::Dist,
+    error::{Error, ErrorKind},
     prelude::*,
+    report::Report,
+    rpmbuild::{BuildMode, Rpmbuild},
     target,
+    target_architecture::{TargetArch, NOARCH},
 };
 use abscissa_core::{Command, Runnable};
 use gumdrop::Options;
-use std::{env, path::PathBuf, process};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process, thread,
+};
 
 /// The `cargo rpm build` subcommand
 #[derive(Command, Debug, Default, Options)]
@@ -20,9 +30,18 @@ pub struct BuildCmd {
     #[options(long = "no-cargo-build")]
     pub no_cargo_build: bool,
 
-    /// Rust target for cross-compilation
+    /// Rust target for cross-compilation (may be given more than once)
     #[options(long = "target")]
-    pub target: Option<String>,
+    pub target: Vec<String>,
+
+    /// RPM target architecture to build for, e.g. `aarch64` or `noarch`
+    /// (may be given more than once)
+    #[options(long = "rpm-arch")]
+    pub rpm_arch: Vec<String>,
+
+    /// Build RPMs for multiple targets in parallel
+    #[options(long = "parallel")]
+    pub parallel: bool,
 
     /// Location to the rpm config directory
     #[options(long = "config")]
@@ -31,6 +50,28 @@ pub struct BuildCmd {
     /// Output path for the built rpm (either a file or directory)
     #[options(long = "output")]
     pub output: Option<String>,
+
+    /// Distribution to build for, e.g. `el8`, selecting its dist tag, spec
+    /// (`.rpm/<name>.<dist>.spec`, if present) and overrides in
+    /// `[package.metadata.rpm.dist.<dist>]`
+    #[options(no_short, long = "dist")]
+    pub dist: Option<Dist>,
+
+    /// Define an rpm macro, e.g. `--define "dist .el9"` (may be given more than once)
+    #[options(short = "D", long = "define")]
+    pub define: Vec<String>,
+
+    /// Which packages to build: `bin`, `src` or `all` (default)
+    #[options(no_short, long = "rpm-mode")]
+    pub rpm_mode: Option<BuildMode>,
+
+    /// Rebuild RPMs even if none of their inputs have changed
+    #[options(no_short, long = "force")]
+    pub force: bool,
+
+    /// Write a JSON report describing the built RPMs to the given file
+    #[options(no_short, long = "report")]
+    pub report: Option<String>,
 }
 
 impl Runnable for BuildCmd {
@@ -41,8 +82,18 @@ impl Runnable for BuildCmd {
         let mut rpm_config_dir = crate_root.join(RPM_CONFIG_DIR);
 
         // Read Cargo.toml
-        let config = app_config();
-        let config = config.package();
+        let mut package = app_config().package().clone();
+
+        // Apply rpmbuild settings given on the command line
+        if let Some(rpm) = package.metadata.as_mut().and_then(|md| md.rpm.as_mut()) {
+            rpm.add_defines(&self.define);
+
+            if let Some(mode) = self.rpm_mode {
+                rpm.set_rpmbuild_mode(mode);
+            }
+        }
+
+        let config = &package;
         let target_dir = target::find_dir().unwrap_or_else(|e| {
             status_err!("error finding target directory: {}", e);
             process::exit(1);
@@ -92,19 +143,225 @@ impl Runnable for BuildCmd {
             output_path = output_path.as_ref().map(convert_to_absolute);
         }
 
-        Builder::new(
-            config,
-            self.verbose,
-            self.no_cargo_build,
-            self.target.as_ref(),
-            output_path.as_ref(),
-            &rpm_config_dir,
-            &target_dir,
-        )
-        .build()
-        .unwrap_or_else(|err| {
+        // Translate rpm architectures into the corresponding rust targets
+        let mut requested_targets = self.target.clone();
+
+        for rpm_arch in &self.rpm_arch {
+            let arch = TargetArch::from_rpm_arch(rpm_arch).unwrap_or_else(|err| {
+                status_err!("{}", err);
+                process::exit(1);
+            });
+
+            let target = arch.as_rust_target_triple().unwrap_or(NOARCH);
+            requested_targets.push(target.to_owned());
+        }
+
+        let targets = builder::build_targets(config, &requested_targets).unwrap_or_else(|err| {
             status_err!("{}", err);
             process::exit(1);
+        });
+
+        // Building several targets into the same output file would clobber it
+        if targets.len() > 1 {
+            if let Some(ref path) = output_path {
+                if !path.ends_with('/') && !Path::new(path).is_dir() {
+                    status_err!(
+                        "output path must be a directory when building multiple targets: {}",
+                        path
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+
+        let mut builders: Vec<Builder> = targets
+            .iter()
+            .map(|target| {
+                let mut builder = Builder::new(
+                    config,
+                    self.verbose,
+                    self.no_cargo_build,
+                    target.as_ref(),
+                    output_path.as_ref(),
+                    &rpm_config_dir,
+                    &target_dir,
+                );
+                builder.force = self.force;
+                builder.dist = self.dist;
+                builder
+            })
+            .collect();
+
+        if builders.len() > 1 {
+            if output_path.is_some() {
+                check_distinct_archs(&builders).unwrap_or_else(|err| {
+                    status_err!("{}", err);
+                    process::exit(1);
+                });
+            }
+
+            build_srpm_once(&mut builders);
+        }
+
+        let labels: Vec<String> = targets
+            .iter()
+            .map(|target| target.clone().unwrap_or_else(|| "(host)".to_owned()))
+            .collect();
+
+        let results = if self.parallel && builders.len() > 1 {
+            let handles: Vec<_> = builders
+                .into_iter()
+                .map(|builder| thread::spawn(move || builder.build()))
+                .collect();
+
+            handles
+                .into_iter()
+                .zip(&labels)
+                .map(|(handle, label)| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(format_err!(ErrorKind::Build, "builder for {} panicked", label).into())
+                    })
+                })
+                .collect::<Vec<_>>()
+        } else {
+            builders
+                .into_iter()
+                .map(|builder| builder.build())
+                .collect()
+        };
+
+        let mut built = vec![];
+        let mut failed = false;
+
+        // Report every failure, not just the first
+        for (result, label) in results.into_iter().zip(&labels) {
+            match result {
+                Ok(rpm) => built.push(rpm),
+                Err(err) => {
+                    if labels.len() > 1 {
+                        status_err!("{}: {}", label, err);
+                    } else {
+                        status_err!("{}", err);
+                    }
+                    failed = true;
+                }
+            }
+        }
+
+        if failed {
+            process::exit(1);
+        }
+
+        if built.len() > 1 {
+            print_summary(&built);
+        }
+
+        if let Some(ref report_path) = self.report {
+            let rpmbuild_path = config.rpm_metadata().and_then(|rpm| rpm.rpmbuild_path());
+            let rpmbuild_version = Rpmbuild::new(rpmbuild_path, false)
+                .and_then(|cmd| cmd.version())
+                .map(|version| version.to_string())
+                .ok();
+
+            Report::new(&built, rpmbuild_version)
+                .and_then(|report| report.write(Path::new(report_path)))
+                .unwrap_or_else(|err| {
+                    status_err!("error writing build report: {}", err);
+                    process::exit(1);
+                });
+
+            status_ok!("Wrote", "build report: {}", report_path);
+        }
+    }
+}
+
+/// Make sure no two targets would write a binary RPM with the same
+/// architecture (and therefore filename) to the shared output path
+fn check_distinct_archs(builders: &[Builder]) -> Result<(), Error> {
+    let mut seen: Vec<(String, &str)> = vec![];
+
+    for builder in builders {
+        let arch = builder.rpm_target_architecture()?;
+        let target = builder.target.as_deref().unwrap_or("(host)");
+
+        if let Some((_, other)) = seen.iter().find(|(seen_arch, _)| *seen_arch == arch) {
+            fail!(
+                ErrorKind::Config,
+                "targets {} and {} would both write {} RPMs to {} (set a separate `--output` for each)",
+                other,
+                target,
+                arch,
+                builder.output_path.as_deref().unwrap_or_default()
+            );
+        }
+
+        seen.push((arch, target));
+    }
+
+    Ok(())
+}
+
+/// The source RPM is the same for every target, so only build it with the
+/// first: the others build binary RPMs only (or nothing, for `--rpm-mode src`)
+fn build_srpm_once(builders: &mut Vec<Builder>) {
+    let mode = builders[0]
+        .config
+        .rpm_metadata()
+        .map(RpmConfig::rpmbuild_mode)
+        .unwrap_or(BuildMode::All);
+
+    match mode {
+        BuildMode::Src => {
+            status_warn!("the source RPM is the same for every target; building it once");
+            builders.truncate(1);
+        }
+        BuildMode::All => {
+            for builder in builders.iter_mut().skip(1) {
+                if let Some(rpm) = builder
+                    .config
+                    .metadata
+                    .as_mut()
+                    .and_then(|md| md.rpm.as_mut())
+                {
+                    rpm.set_rpmbuild_mode(BuildMode::Bin);
+                }
+            }
+        }
+        BuildMode::Bin => (),
+    }
+}
+
+/// Print a table of the RPMs built for each target
+fn print_summary(built: &[BuiltRpm]) {
+    let rows: Vec<(String, String, String)> = built
+        .iter()
+        .map(|rpm| {
+            (
+                rpm.target.clone().unwrap_or_else(|| "(host)".to_owned()),
+                rpm.arch.clone(),
+                rpm.rpm
+                    .iter()
+                    .chain(rpm.srpm.iter())
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )
         })
+        .collect();
+
+    let target_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+    let arch_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0);
+
+    status_ok!("Summary", "built {} RPMs", rows.len());
+
+    for (target, arch, rpm_file) in &rows {
+        println!(
+            "    {:target_width$}  {:arch_width$}  {}",
+            target,
+            arch,
+            rpm_file,
+            target_width = target_width,
+            arch_width = arch_width
+        );
     }
 }
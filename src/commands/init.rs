@@ -2,25 +2,23 @@
 
 use crate::{
     builder::RPM_CONFIG_DIR,
-    config::{self, PackageConfig, CARGO_CONFIG_FILE},
+    config::{self, CARGO_CONFIG_FILE},
+    dist::Dist,
     error::Error,
     prelude::*,
     target::TargetType,
-    templates::{ServiceParams, SpecParams},
+    templates::SpecParams,
 };
 use abscissa_core::Command;
 use gumdrop::Options;
 use std::{
     env,
-    fs::{self, File, OpenOptions},
+    fs::{self, OpenOptions},
     io::Write,
     path::{Path, PathBuf},
     process,
 };
 
-/// Directory in which systemd service unit configs reside
-const SYSTEMD_DIR: &str = "/usr/lib/systemd/system";
-
 /// The `cargo rpm init` subcommand
 #[derive(Command, Debug, Default, Options)]
 pub struct InitCmd {
@@ -32,7 +30,7 @@ pub struct InitCmd {
     #[options(no_short, long = "sbin")]
     pub sbin: bool,
 
-    /// Path to the systemd service unit config template
+    /// Path to a template for the systemd service unit config (implies `--systemd`)
     #[options(no_short, long = "service")]
     pub service: Option<String>,
 
@@ -51,6 +49,11 @@ pub struct InitCmd {
     /// Where should the generated spec file be saved, defaults to `.rpm`
     #[options(long = "output")]
     pub output: Option<String>,
+
+    /// Distribution to generate the spec for, e.g. `el8` or `suse`. If the
+    /// config directory already exists, adds a `<name>.<dist>.spec` to it.
+    #[options(no_short, long = "dist")]
+    pub dist: Option<Dist>,
 }
 
 impl Runnable for InitCmd {
@@ -92,6 +95,43 @@ impl InitCmd {
             None => config.package().name.to_owned(),
         };
 
+        // Check if we're creating a systemd service unit for this crate
+        let service_name = if self.service.is_some() || self.systemd {
+            Some(format!("{}.service", pkg_name))
+        } else {
+            None
+        };
+
+        // Autodetect whether to place target files in `/usr/bin` or `/usr/sbin`
+        let use_sbin = self.sbin;
+
+        // Add a spec for another distribution to an existing `.rpm`
+        // (with `--force`, `.rpm` is recreated instead)
+        let add_dist_spec = rpm_config_dir.exists() && !self.force;
+
+        if add_dist_spec {
+            if let Some(dist) = self.dist {
+                let spec_path = rpm_config_dir.join(format!("{}.{}.spec", pkg_name, dist));
+
+                if spec_path.exists() {
+                    status_err!(
+                        "destination `{}` already exists!",
+                        spec_path.canonicalize().unwrap().display()
+                    );
+                    process::exit(1);
+                }
+
+                let spec_params = SpecParams::new(
+                    pkg_name,
+                    config.package(),
+                    service_name,
+                    use_sbin,
+                    Some(dist),
+                );
+                return render_spec(&spec_path, &self.template, &spec_params);
+            }
+        }
+
         // Check if `.rpm` already exists
         if rpm_config_dir.exists() {
             if self.force {
@@ -107,16 +147,6 @@ impl InitCmd {
             }
         }
 
-        // Check if we're creating a systemd service unit for this crate
-        let service_name = if self.service.is_some() || self.systemd {
-            Some(format!("{}.service", pkg_name))
-        } else {
-            None
-        };
-
-        // Autodetect whether to place target files in `/usr/bin` or `/usr/sbin`
-        let use_sbin = self.sbin;
-
         // Autodetect target types
         let targets = match TargetType::detect(&crate_root)? {
             TargetType::Lib => {
@@ -147,29 +177,35 @@ impl InitCmd {
             &config.package(),
             service_name.clone(),
             use_sbin,
+            self.dist,
         );
         render_spec(&spec_path, &self.template, &spec_params)?;
 
-        // (Optional) Render `.rpm/<cratename>.service` (systemd service unit config)
-        if let Some(ref service) = service_name {
-            render_service(
-                &rpm_config_dir.join(service),
-                &self.service,
-                config.package(),
-            )?;
-        }
+        // (Optional) Copy the systemd service unit template to
+        // `.rpm/<cratename>.service.hbs`, from which the unit is rendered
+        let service_template = match (&service_name, &self.service) {
+            (Some(service), Some(template)) => {
+                let template_file = PathBuf::from(format!("{}.hbs", service));
+                copy_template(Path::new(template), &rpm_config_dir.join(&template_file))?;
+                Some(template_file)
+            }
+            _ => None,
+        };
 
         // Update Cargo.toml with RPM metadata
         if config.package().rpm_metadata().is_some() && !self.force {
             status_warn!("not updating Cargo.toml because [package.metadata.rpm] already present");
         } else {
-            let mut extra_files = vec![];
-            if let Some(ref service) = service_name {
-                extra_files.push(PathBuf::from(SYSTEMD_DIR).join(service.clone()));
-            }
-
             let bin_dir: PathBuf = if use_sbin { "/usr/sbin" } else { "/usr/bin" }.into();
-            config::append_rpm_metadata(&pkg_name, &cargo_toml, &targets, &extra_files, &bin_dir)?;
+            config::append_rpm_metadata(
+                &pkg_name,
+                &cargo_toml,
+                &targets,
+                &bin_dir,
+                self.dist,
+                service_name.as_deref(),
+                service_template.as_deref(),
+            )?;
         }
 
         status_ok!(
@@ -208,24 +244,11 @@ fn render_spec(
     Ok(())
 }
 
-/// Render this package's systemd service unit config (if enabled)
-fn render_service(
-    service_path: &Path,
-    template_path_str: &Option<String>,
-    package_config: &PackageConfig,
-) -> Result<(), Error> {
-    let service_params = ServiceParams::from(package_config);
-    let template_path = template_path_str.as_ref().map(PathBuf::from);
-    let service_rendered = service_params.render(template_path.as_ref().map(|t| t.as_ref()))?;
+/// Copy a systemd service unit template into the config directory
+fn copy_template(template_path: &Path, dest_path: &Path) -> Result<(), Error> {
+    fs::copy(template_path, dest_path)?;
 
-    let mut service_file = File::create(service_path)?;
-    service_file.write_all(service_rendered.as_bytes())?;
-
-    status_ok!(
-        "Rendered",
-        "{}",
-        service_path.canonicalize().unwrap().display()
-    );
+    status_ok!("Copied", "{}", dest_path.canonicalize().unwrap().display());
 
     Ok(())
 }
@@ -0,0 +1,378 @@
+//! Compression settings for the release archive and the RPM payload
+
+use crate::{
+    error::{Error, ErrorKind},
+    prelude::*,
+};
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    fmt,
+    fs::File,
+    io::{self, Write},
+    mem, panic,
+    str::FromStr,
+    thread::{self, JoinHandle},
+};
+use xz2::{stream::MtStreamBuilder, write::XzEncoder};
+
+/// Highest zstd level rpm accepts for payloads
+const MAX_PAYLOAD_ZSTD_LEVEL: u32 = 19;
+
+/// Compression codecs
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Codec {
+    /// No compression
+    None,
+
+    /// gzip
+    Gzip,
+
+    /// xz
+    Xz,
+
+    /// zstd
+    Zstd,
+}
+
+impl Codec {
+    /// Default compression level for this codec
+    fn default_level(self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Gzip => 6,
+            Codec::Xz => 6,
+            Codec::Zstd => 3,
+        }
+    }
+
+    /// Extension of a tarball compressed with this codec
+    pub fn tarball_extension(self) -> &'static str {
+        match self {
+            Codec::None => "tar",
+            Codec::Gzip => "tar.gz",
+            Codec::Xz => "tar.xz",
+            Codec::Zstd => "tar.zst",
+        }
+    }
+
+    /// Highest compression level supported by this codec
+    fn max_level(self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Gzip | Codec::Xz => 9,
+            Codec::Zstd => 22,
+        }
+    }
+}
+
+/// A codec and compression level, e.g. `zstd:19`
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Compression {
+    /// Compression codec
+    pub codec: Codec,
+
+    /// Compression level
+    pub level: u32,
+}
+
+impl Compression {
+    /// Check that rpm can write a payload with this compression: it only
+    /// accepts zstd levels 1 to 19, unlike the zstd library
+    pub fn check_payload(self) -> Result<(), Error> {
+        if self.codec == Codec::Zstd && (self.level < 1 || self.level > MAX_PAYLOAD_ZSTD_LEVEL) {
+            fail!(
+                ErrorKind::Config,
+                "invalid zstd payload level {} (rpm supports levels 1 to {})",
+                self.level,
+                MAX_PAYLOAD_ZSTD_LEVEL
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Value for `%_binary_payload` which selects this compression, using the
+    /// given number of threads (if the codec supports it)
+    pub fn payload_macro_value(self, threads: Option<u32>) -> String {
+        let threads = match (self.codec, threads) {
+            (Codec::Xz, Some(n)) | (Codec::Zstd, Some(n)) => format!("T{}", n),
+            _ => String::new(),
+        };
+
+        let io = match self.codec {
+            Codec::None => "ufdio",
+            Codec::Gzip => "gzdio",
+            Codec::Xz => "xzdio",
+            Codec::Zstd => "zstdio",
+        };
+
+        format!("w{}{}.{}", self.level, threads, io)
+    }
+
+    /// Create an encoder which compresses the given file using this
+    /// compression, using the given number of threads (if supported). With
+    /// one thread (or `None`) every codec uses its single-threaded encoder.
+    pub fn encoder(self, file: File, threads: Option<u32>) -> Result<Encoder, Error> {
+        Ok(match self.codec {
+            Codec::None => Encoder::None(file),
+            Codec::Gzip => match threads {
+                Some(n) if n > 1 => Encoder::ParallelGzip(ParallelGzEncoder::new(
+                    file,
+                    flate2::Compression::new(self.level),
+                    n as usize,
+                )),
+                _ => Encoder::Gzip(GzEncoder::new(file, flate2::Compression::new(self.level))),
+            },
+            Codec::Xz => match threads {
+                Some(n) if n > 1 => {
+                    let stream = MtStreamBuilder::new()
+                        .preset(self.level)
+                        .threads(n)
+                        .encoder()
+                        .map_err(|e| {
+                            format_err!(ErrorKind::Io, "error creating xz encoder: {}", e)
+                        })?;
+
+                    Encoder::Xz(XzEncoder::new_stream(file, stream))
+                }
+                _ => Encoder::Xz(XzEncoder::new(file, self.level)),
+            },
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::new(file, self.level as i32)?;
+
+                if let Some(n) = threads.filter(|&n| n > 1) {
+                    encoder.multithread(n)?;
+                }
+
+                Encoder::Zstd(encoder)
+            }
+        })
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            codec: Codec::Gzip,
+            level: Codec::Gzip.default_level(),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut parts = s.splitn(2, ':');
+
+        let codec = match parts.next().unwrap_or("") {
+            "none" => Codec::None,
+            "gzip" | "gz" => Codec::Gzip,
+            "xz" => Codec::Xz,
+            "zstd" => Codec::Zstd,
+            other => fail!(
+                ErrorKind::Config,
+                "unknown compression codec {:?} (expected none, gzip, xz or zstd)",
+                other
+            ),
+        };
+
+        let level = match parts.next() {
+            Some(level) => level.parse()?,
+            None => codec.default_level(),
+        };
+
+        if level > codec.max_level() {
+            fail!(
+                ErrorKind::Config,
+                "invalid compression level {} in {:?} (maximum is {})",
+                level,
+                s,
+                codec.max_level()
+            );
+        }
+
+        Ok(Self { codec, level })
+    }
+}
+
+impl TryFrom<String> for Compression {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let codec = match self.codec {
+            Codec::None => return write!(f, "none"),
+            Codec::Gzip => "gzip",
+            Codec::Xz => "xz",
+            Codec::Zstd => "zstd",
+        };
+
+        write!(f, "{}:{}", codec, self.level)
+    }
+}
+
+/// Writer which compresses data into a file
+pub enum Encoder {
+    /// Uncompressed
+    None(File),
+
+    /// gzip
+    Gzip(GzEncoder<File>),
+
+    /// gzip, compressed in parallel
+    ParallelGzip(ParallelGzEncoder<File>),
+
+    /// xz
+    Xz(XzEncoder<File>),
+
+    /// zstd
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Encoder {
+    /// Finish compressing, returning the underlying file
+    pub fn finish(self) -> io::Result<File> {
+        match self {
+            Encoder::None(file) => Ok(file),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::ParallelGzip(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(file) => file.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::ParallelGzip(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(file) => file.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::ParallelGzip(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Size of the blocks compressed independently by `ParallelGzEncoder`
+const PARALLEL_GZIP_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// gzip encoder which splits its input into blocks and compresses each of
+/// them as a separate gzip member on a worker thread.
+///
+/// The output is a multi-member gzip stream, which `gzip -d` (and therefore
+/// `%setup`) decompresses as if it were a single member.
+pub struct ParallelGzEncoder<W: Write> {
+    /// Underlying writer
+    inner: W,
+
+    /// Compression level
+    level: flate2::Compression,
+
+    /// Maximum number of blocks being compressed at once
+    threads: usize,
+
+    /// Block currently being filled
+    block: Vec<u8>,
+
+    /// Blocks being compressed, in output order
+    pending: VecDeque<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    /// Create a new encoder using the given number of threads
+    pub fn new(inner: W, level: flate2::Compression, threads: usize) -> Self {
+        Self {
+            inner,
+            level,
+            threads: threads.max(1),
+            block: Vec::with_capacity(PARALLEL_GZIP_BLOCK_SIZE),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Finish compressing, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.compress_block()?;
+
+        while !self.pending.is_empty() {
+            self.write_next_block()?;
+        }
+
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Hand the current block to a worker thread, first waiting for the
+    /// oldest one to complete if all threads are busy
+    fn compress_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        if self.pending.len() >= self.threads {
+            self.write_next_block()?;
+        }
+
+        let block = mem::replace(
+            &mut self.block,
+            Vec::with_capacity(PARALLEL_GZIP_BLOCK_SIZE),
+        );
+        let level = self.level;
+
+        self.pending.push_back(thread::spawn(move || {
+            let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() / 2), level);
+            encoder.write_all(&block)?;
+            encoder.finish()
+        }));
+
+        Ok(())
+    }
+
+    /// Wait for the oldest pending block and write it out
+    fn write_next_block(&mut self) -> io::Result<()> {
+        if let Some(handle) = self.pending.pop_front() {
+            let compressed = handle.join().unwrap_or_else(|e| panic::resume_unwind(e))?;
+            self.inner.write_all(&compressed)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(PARALLEL_GZIP_BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..len]);
+
+        if self.block.len() == PARALLEL_GZIP_BLOCK_SIZE {
+            self.compress_block()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,6 +1,13 @@
 //! `Cargo.toml` parser specialized for the `cargo rpm` use case
 
-use crate::{error::Error, prelude::*};
+use crate::{
+    compression::Compression,
+    dist::Dist,
+    error::{Error, ErrorKind},
+    prelude::*,
+    rpmbuild::BuildMode,
+    target_architecture::TargetArch,
+};
 use serde::Deserialize;
 use std::{
     collections::BTreeMap,
@@ -79,6 +86,18 @@ impl PackageConfig {
             .unwrap_or(&self.name)
     }
 
+    /// Get a copy of this config with the overrides for the given RPM target
+    /// architecture and distribution (if any) merged on top of it
+    pub fn with_overrides(&self, arch: &str, dist: Option<Dist>) -> Result<Self, Error> {
+        let mut config = self.clone();
+
+        if let Some(rpm) = config.metadata.as_mut().and_then(|md| md.rpm.as_mut()) {
+            rpm.apply_overrides(arch, dist)?;
+        }
+
+        Ok(config)
+    }
+
     /// Get the version and release for this package
     pub fn version(&self) -> (String, String) {
         let version_split: Vec<&str> = self.version.split('-').collect();
@@ -116,16 +135,192 @@ pub struct RpmConfig {
     pub cargo: Option<CargoFlags>,
 
     /// Target configuration: a map of target binaries to their file config
+    /// (keyed by the binary's name, unless `bin` is given)
     pub targets: BTreeMap<String, FileConfig>,
 
-    /// Extra files (taken from the config directory) to include in the RPM
+    /// Extra files (taken from the config directory) to include in the RPM.
+    /// Keys may be glob patterns, e.g. `"conf.d/*.toml"`, in which case
+    /// `path` is the directory the matching files are installed into.
     pub files: Option<BTreeMap<String, FileConfig>>,
 
+    /// Glob patterns of files in the config directory to never include,
+    /// e.g. `["*~", ".gitkeep"]`
+    pub exclude: Option<Vec<String>>,
+
+    /// Umask applied to the permissions of extra files which have no
+    /// configured `mode` (default `"022"`)
+    pub umask: Option<String>,
+
     /// Extra commands to launch after building
     pub build_hooks: Option<BTreeMap<String, Vec<String>>>,
 
     /// Target architecture passed to `rpmbuild`
     pub target_architecture: Option<String>,
+
+    /// Options for invoking `rpmbuild`
+    pub rpmbuild: Option<RpmbuildConfig>,
+
+    /// Compression used for the release archive and the RPM payload
+    pub compression: Option<CompressionConfig>,
+
+    /// Targets and files for specific RPM target architectures, e.g.
+    /// `[package.metadata.rpm.arch.aarch64.files]`
+    pub arch: Option<BTreeMap<String, RpmOverrides>>,
+
+    /// Targets and files for specific distributions (selected with `--dist`),
+    /// e.g. `[package.metadata.rpm.dist.el8.files]`. These are merged after
+    /// (and so take precedence over) the architecture overrides.
+    pub dist: Option<BTreeMap<String, RpmOverrides>>,
+
+    /// Distribution to build for when no `--dist` is given, e.g. `"el9"`
+    pub default_dist: Option<Dist>,
+
+    /// systemd units to generate, keyed by unit name, e.g.
+    /// `[package.metadata.rpm.systemd."foo.service"]`
+    pub systemd: Option<BTreeMap<String, UnitConfig>>,
+
+    /// System users (each with a group of the same name) to create on
+    /// install, keyed by name, e.g. `[package.metadata.rpm.users.foo]`
+    pub users: Option<BTreeMap<String, UserConfig>>,
+
+    /// tmpfiles.d entries to generate, keyed by path, e.g.
+    /// `[package.metadata.rpm.tmpfiles."/run/foo"]`
+    pub tmpfiles: Option<BTreeMap<String, TmpfileConfig>>,
+
+    /// logrotate policies to generate, keyed by the (glob) path of the logs,
+    /// e.g. `[package.metadata.rpm.logrotate."/var/log/foo/*.log"]`
+    pub logrotate: Option<BTreeMap<String, LogrotateConfig>>,
+
+    /// Login shell environment to generate a `/etc/profile.d` script for
+    pub profile_d: Option<ProfileConfig>,
+}
+
+impl RpmConfig {
+    /// Get the configured path to `rpmbuild` (if any)
+    pub fn rpmbuild_path(&self) -> Option<&Path> {
+        self.rpmbuild.as_ref().and_then(|r| r.path.as_deref())
+    }
+
+    /// Get the configured `rpmbuild` mode
+    pub fn rpmbuild_mode(&self) -> BuildMode {
+        self.rpmbuild
+            .as_ref()
+            .and_then(|r| r.mode)
+            .unwrap_or(BuildMode::All)
+    }
+
+    /// Set the `rpmbuild` mode
+    pub fn set_rpmbuild_mode(&mut self, mode: BuildMode) {
+        self.rpmbuild.get_or_insert_with(Default::default).mode = Some(mode);
+    }
+
+    /// Merge the overrides for the given RPM target architecture and
+    /// distribution (if any) into this config
+    pub fn apply_overrides(&mut self, arch: &str, dist: Option<Dist>) -> Result<(), Error> {
+        // Native builds use whatever rpmbuild says the host is, so names
+        // without a Rust target may still be used (e.g. a custom `i386`)
+        for name in self.arch.iter().flat_map(BTreeMap::keys) {
+            if name != arch && TargetArch::from_rpm_arch(name).is_err() {
+                status_warn!(
+                    "unknown RPM target architecture {:?} in [package.metadata.rpm.arch] \
+                     (its overrides only apply to native builds on such hosts)",
+                    name
+                );
+            }
+        }
+
+        let mut overrides: Vec<RpmOverrides> = self
+            .arch
+            .as_ref()
+            .and_then(|arches| arches.get(arch))
+            .into_iter()
+            .cloned()
+            .collect();
+
+        // Distributions may be named by any of their aliases, e.g. `rhel8`
+        for (name, dist_overrides) in self.dist.iter().flatten() {
+            match name.parse::<Dist>() {
+                Ok(name_dist) if Some(name_dist) == dist => overrides.push(dist_overrides.clone()),
+                Ok(_) => (),
+                Err(_) => fail!(
+                    ErrorKind::Config,
+                    "unknown distribution {:?} in [package.metadata.rpm.dist]",
+                    name
+                ),
+            }
+        }
+
+        for overrides in overrides {
+            self.targets.extend(overrides.targets.into_iter().flatten());
+
+            if let Some(files) = overrides.files {
+                self.files.get_or_insert_with(BTreeMap::new).extend(files);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add macro definitions to pass to `rpmbuild`
+    pub fn add_defines(&mut self, defines: &[String]) {
+        self.rpmbuild
+            .get_or_insert_with(Default::default)
+            .defines
+            .get_or_insert_with(Vec::new)
+            .extend(defines.iter().cloned());
+    }
+}
+
+/// Targets and files overriding those of the base config for a particular
+/// architecture or distribution. Entries replace base entries with the same
+/// key, and are added otherwise.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RpmOverrides {
+    /// Target binaries, as in `[package.metadata.rpm.targets]`
+    pub targets: Option<BTreeMap<String, FileConfig>>,
+
+    /// Extra files, as in `[package.metadata.rpm.files]`
+    pub files: Option<BTreeMap<String, FileConfig>>,
+}
+
+/// Options for invoking `rpmbuild`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RpmbuildConfig {
+    /// Path to the `rpmbuild` executable (overridden by `$RPMBUILD`)
+    pub path: Option<PathBuf>,
+
+    /// Macro definitions passed as `-D`, e.g. `"dist .el9"`
+    pub defines: Option<Vec<String>>,
+
+    /// Additional arguments to pass to `rpmbuild`, e.g. `"--nocheck"`
+    pub args: Option<Vec<String>>,
+
+    /// Which packages to build: `bin`, `src` or `all` (default)
+    pub mode: Option<BuildMode>,
+}
+
+/// Compression settings, e.g. `{ archive = "zstd:19", payload = "xz:6" }`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CompressionConfig {
+    /// Compression for the intermediate release archive (default `gzip:6`)
+    pub archive: Option<Compression>,
+
+    /// Compression for the RPM payload (sets `%_binary_payload`)
+    pub payload: Option<Compression>,
+
+    /// Number of threads to hash and compress the release archive with
+    /// (default: one per CPU), and to compress xz and zstd payloads with
+    /// (default: rpm's own choice)
+    pub threads: Option<u32>,
+}
+
+impl CompressionConfig {
+    /// Number of threads to hash and compress the release archive with
+    pub fn archive_threads(&self) -> u32 {
+        self.threads
+            .unwrap_or_else(|| num_cpus::get() as u32)
+            .max(1)
+    }
 }
 
 /// Options for creating the release artifact
@@ -137,24 +332,254 @@ pub struct CargoFlags {
     /// The target - defaults to the host architecture
     pub target: Option<String>,
 
+    /// Multiple targets to build (one RPM per target)
+    pub targets: Option<Vec<String>>,
+
     /// Flags to pass to cargo build
     pub buildflags: Option<Vec<String>>,
 }
 
 /// Properties of a file to be included in the final RPM
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct FileConfig {
     /// Absolute path where the file should reside after installation
     pub path: PathBuf,
 
+    /// Additional paths at which to install the same target binary
+    /// (as hardlinks), e.g. `["/usr/libexec/foo/server"]`
+    pub extra_paths: Option<Vec<PathBuf>>,
+
+    /// Name of the cargo binary target to install, if different from the
+    /// key in `targets`, e.g. `{ bin = "server", path = "/usr/bin/acme-server" }`
+    pub bin: Option<String>,
+
+    /// Where to take the file from, if not named by its key: a path (or glob)
+    /// relative to `crate:`, `config:` (the `.rpm` directory), `target:` (the
+    /// target directory for the build profile) or `out_dir:<crate>/` (the
+    /// `OUT_DIR` of a crate's build script), e.g. `"out_dir:foo/foo.1"`
+    pub source: Option<String>,
+
     /// Username of the owner of the file
     pub username: Option<String>,
 
     /// Groupname of the owner of the file
     pub groupname: Option<String>,
 
-    /// Mode of the file (default 755 for targets, 644 for extra files)
+    /// Mode of the file (default 755 for targets; for extra files, the mode
+    /// of the source file minus the umask). For directories and globs, the
+    /// mode of every file they contain.
     pub mode: Option<String>,
+
+    /// Install a symlink pointing at this target instead of a file, e.g.
+    /// `{ path = "/usr/bin/foo-alias", link_target = "foo" }`
+    pub link_target: Option<PathBuf>,
+
+    /// Type of the entry: `file` (default) or `dir` for a directory owned by
+    /// the package, e.g. `{ path = "/var/lib/foo", type = "dir", mode = "750" }`
+    #[serde(rename = "type")]
+    pub file_type: Option<FileType>,
+
+    /// Glob patterns of files to leave out when this entry is a directory or
+    /// a glob (patterns without a `/` match file names at any depth)
+    pub exclude: Option<Vec<String>>,
+}
+
+/// Types of entries which can be installed by the RPM
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    /// Regular file (or a directory of files in the config directory)
+    File,
+
+    /// Directory owned by the package
+    Dir,
+}
+
+/// A systemd unit (service, socket or timer) to generate. Names ending in
+/// `@.service` are template units.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct UnitConfig {
+    /// Description of the unit (defaults to the package description)
+    pub description: Option<String>,
+
+    /// Handlebars template to render the unit from, relative to the config
+    /// directory (defaults to the built-in template for the unit's type)
+    pub template: Option<PathBuf>,
+
+    /// Target (in `[package.metadata.rpm.targets]`) a service runs. Defaults
+    /// to the target named after the unit, or the only target.
+    pub target: Option<String>,
+
+    /// Arguments passed to a service's target, e.g. `["serve", "--instance", "%i"]`
+    pub args: Option<Vec<String>>,
+
+    /// Type of a service: `simple` (default), `exec`, `forking`, `oneshot`,
+    /// `notify` or `idle`
+    #[serde(rename = "type")]
+    pub service_type: Option<String>,
+
+    /// User a service runs as (default root)
+    pub user: Option<String>,
+
+    /// Group a service runs as (default the user's primary group)
+    pub group: Option<String>,
+
+    /// Environment file read by a service (default `/etc/sysconfig/<name>`),
+    /// which is generated and installed as `%config(noreplace)`
+    pub environment_file: Option<PathBuf>,
+
+    /// Variables set in the generated environment file, e.g. `{ RUST_LOG = "info" }`
+    pub environment: Option<BTreeMap<String, String>>,
+
+    /// Working directory of a service
+    pub working_directory: Option<PathBuf>,
+
+    /// When to restart a service: `on-failure` (default), `always`,
+    /// `on-abnormal`, `on-abort`, `on-success`, `on-watchdog` or `no`
+    pub restart: Option<String>,
+
+    /// Capabilities a service keeps, e.g. `["CAP_NET_BIND_SERVICE"]`. They're
+    /// granted as ambient capabilities to services with a `user`, which
+    /// otherwise keep no capabilities when hardened.
+    pub capabilities: Option<Vec<String>>,
+
+    /// Apply the default sandboxing options to a service (default true)
+    pub hardening: Option<bool>,
+
+    /// Directory below `/var/lib` for a service's state (defaults to the
+    /// service's name when hardened)
+    pub state_directory: Option<String>,
+
+    /// Directory below `/run` which exists while a service runs
+    pub runtime_directory: Option<String>,
+
+    /// Directory below `/var/log` for a service's logs
+    pub logs_directory: Option<String>,
+
+    /// Other paths a hardened service may write to
+    pub read_write_paths: Option<Vec<String>>,
+
+    /// Unit activated by a socket or timer (defaults to the service with the
+    /// same name)
+    pub unit: Option<String>,
+
+    /// Stream sockets to listen on, e.g. `["8080", "/run/foo.sock"]`
+    pub listen_stream: Option<Vec<String>>,
+
+    /// Datagram sockets to listen on
+    pub listen_datagram: Option<Vec<String>>,
+
+    /// Calendar events which trigger a timer, e.g. `"daily"`
+    pub on_calendar: Option<String>,
+
+    /// Time after boot at which a timer triggers, e.g. `"15min"`
+    pub on_boot_sec: Option<String>,
+
+    /// Time after its unit was last activated at which a timer triggers
+    pub on_unit_active_sec: Option<String>,
+
+    /// Trigger a timer on boot if it was missed while the system was down?
+    pub persistent: Option<bool>,
+
+    /// Target which wants this unit when it's enabled (defaults to
+    /// `multi-user.target`, `sockets.target` or `timers.target`)
+    pub wanted_by: Option<String>,
+
+    /// Instance of a template unit (e.g. `foo@.service`) to enable. Template
+    /// units without one aren't enabled on install.
+    pub default_instance: Option<String>,
+}
+
+/// A system user to create on install, along with a group of the same name
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct UserConfig {
+    /// Description of the user (the GECOS field), e.g. `"Foo daemon"`
+    pub description: Option<String>,
+
+    /// UID of the user (and GID of its group). Allocated when the package is
+    /// installed by default.
+    pub uid: Option<u32>,
+
+    /// Home directory of the user (default `/`), which isn't created
+    pub home: Option<PathBuf>,
+
+    /// Login shell of the user (default `/sbin/nologin`)
+    pub shell: Option<PathBuf>,
+
+    /// Supplementary groups of the user, which are created if they don't
+    /// exist, e.g. `["adm"]`
+    pub groups: Option<Vec<String>>,
+
+    /// Is the user created by another package (e.g. a dependency)? If so,
+    /// it may own files but isn't created by this one.
+    pub external: Option<bool>,
+}
+
+/// An entry of the generated tmpfiles.d snippet, which systemd creates (and
+/// cleans up) at boot and when the package is installed
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TmpfileConfig {
+    /// Type of the entry, e.g. `d` (default) for a directory, `f` for a file
+    /// or `L` for a symlink (see `tmpfiles.d(5)`)
+    #[serde(rename = "type")]
+    pub entry_type: Option<String>,
+
+    /// Mode of the file or directory, e.g. `"0750"`
+    pub mode: Option<String>,
+
+    /// User owning the file or directory (default root)
+    pub user: Option<String>,
+
+    /// Group owning the file or directory (default root)
+    pub group: Option<String>,
+
+    /// Age after which files are cleaned up, e.g. `"10d"`
+    pub age: Option<String>,
+
+    /// Argument of the entry, e.g. the target of a symlink
+    pub argument: Option<String>,
+}
+
+/// A logrotate policy for a set of log files
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LogrotateConfig {
+    /// How often logs are rotated: `daily`, `weekly` (default), `monthly`
+    /// or `yearly`
+    pub frequency: Option<String>,
+
+    /// Number of rotated logs to keep (default 4)
+    pub rotate: Option<u32>,
+
+    /// Rotate logs which grow larger than this, however often, e.g. `"100M"`
+    pub size: Option<String>,
+
+    /// Compress rotated logs (default true)
+    pub compress: Option<bool>,
+
+    /// Truncate logs in place rather than moving them, for daemons which
+    /// can't reopen their logs (default false)
+    pub copytruncate: Option<bool>,
+
+    /// Mode, owner and group of new logs, e.g. `"0640 foo foo"`
+    pub create: Option<String>,
+
+    /// User and group to rotate as, which logrotate needs for directories
+    /// writable by anyone but root, e.g. `"foo foo"`
+    pub su: Option<String>,
+
+    /// Commands run after logs are rotated, e.g.
+    /// `["systemctl kill -s HUP foo.service"]`
+    pub postrotate: Option<Vec<String>>,
+}
+
+/// Login shell environment set by the generated `/etc/profile.d` script
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ProfileConfig {
+    /// Directories prepended to `PATH`, e.g. `["/opt/foo/bin"]`
+    pub path: Option<Vec<PathBuf>>,
+
+    /// Variables exported, e.g. `{ FOO_HOME = "/opt/foo" }`
+    pub environment: Option<BTreeMap<String, String>>,
 }
 
 /// Render `package.metadata.rpm` section to include in Cargo.toml
@@ -162,8 +587,10 @@ pub fn append_rpm_metadata(
     pkg_name: &str,
     path: &Path,
     targets: &[String],
-    extra_files: &[PathBuf],
     bin_dir: &Path,
+    dist: Option<Dist>,
+    service: Option<&str>,
+    service_template: Option<&Path>,
 ) -> Result<(), Error> {
     assert!(!targets.is_empty(), "no target configuration?!");
 
@@ -174,6 +601,10 @@ pub fn append_rpm_metadata(
     writeln!(cargo_toml, "\n[package.metadata.rpm]")?;
     writeln!(cargo_toml, "package = \"{}\"", pkg_name)?;
 
+    if let Some(dist) = dist {
+        writeln!(cargo_toml, "default_dist = \"{}\"", dist)?;
+    }
+
     // Flags to pass to cargo when doing a release
     // TODO: use serde serializer?
     writeln!(cargo_toml, "\n[package.metadata.rpm.cargo]")?;
@@ -191,27 +622,16 @@ pub fn append_rpm_metadata(
         )?;
     }
 
-    // These files come from the .rpm directory
-    if !extra_files.is_empty() {
-        writeln!(cargo_toml, "\n[package.metadata.rpm.files]")?;
+    // systemd service unit running the target named after the package
+    if let Some(service) = service {
+        writeln!(cargo_toml, "\n[package.metadata.rpm.systemd.{:?}]", service)?;
 
-        for path in extra_files {
-            if !path.is_absolute() {
-                status_err!("path is not absolute: {}", path.display());
-                process::exit(1);
-            }
+        if let Some(target) = targets.iter().find(|t| *t == pkg_name).or(targets.first()) {
+            writeln!(cargo_toml, "target = {:?}", target)?;
+        }
 
-            let file = path.file_name().unwrap_or_else(|| {
-                status_err!("path has no filename: {}", path.display());
-                process::exit(1);
-            });
-
-            writeln!(
-                cargo_toml,
-                "{:?} = {{ path = {:?} }}",
-                file.to_str().unwrap(),
-                path.display()
-            )?;
+        if let Some(template) = service_template {
+            writeln!(cargo_toml, "template = {:?}", template)?;
         }
     }
 
@@ -0,0 +1,206 @@
+//! Distribution profiles, which adapt generated specs and builds to the
+//! conventions of Fedora, RHEL (and its rebuilds), openSUSE/SLES and
+//! Amazon Linux
+
+use crate::{
+    error::{Error, ErrorKind},
+    license::LicenseStyle,
+    prelude::*,
+};
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+/// Distributions we have a profile for
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Dist {
+    /// Fedora
+    Fedora,
+
+    /// RHEL 7, CentOS 7 and other Enterprise Linux 7 rebuilds
+    El7,
+
+    /// RHEL 8 and other Enterprise Linux 8 distributions
+    El8,
+
+    /// RHEL 9 and other Enterprise Linux 9 distributions
+    El9,
+
+    /// openSUSE and SUSE Linux Enterprise
+    Suse,
+
+    /// Amazon Linux 2
+    Amzn2,
+
+    /// Amazon Linux 2023
+    Amzn2023,
+}
+
+impl Dist {
+    /// Canonical name of this distribution, as used with `--dist`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Dist::Fedora => "fedora",
+            Dist::El7 => "el7",
+            Dist::El8 => "el8",
+            Dist::El9 => "el9",
+            Dist::Suse => "suse",
+            Dist::Amzn2 => "amzn2",
+            Dist::Amzn2023 => "amzn2023",
+        }
+    }
+
+    /// Value of the `%dist` macro for this distribution. Fedora and SUSE
+    /// releases each have their own (or none), so the build host's is used.
+    pub fn dist_tag(self) -> Option<&'static str> {
+        match self {
+            Dist::Fedora | Dist::Suse => None,
+            Dist::El7 => Some(".el7"),
+            Dist::El8 => Some(".el8"),
+            Dist::El9 => Some(".el9"),
+            Dist::Amzn2 => Some(".amzn2"),
+            Dist::Amzn2023 => Some(".amzn2023"),
+        }
+    }
+
+    /// Style of the `License` tag expected by this distribution
+    pub fn license_style(self) -> LicenseStyle {
+        match self {
+            Dist::Fedora | Dist::Suse => LicenseStyle::Spdx,
+            _ => LicenseStyle::Fedora,
+        }
+    }
+
+    /// Does this distribution ship rpm 4.11, which expects the `Group` and
+    /// `BuildRoot` tags and a `%clean` section in specs?
+    pub fn is_legacy(self) -> bool {
+        matches!(self, Dist::El7 | Dist::Amzn2)
+    }
+
+    /// Package providing the systemd RPM macros (e.g. `%{_unitdir}`)
+    pub fn systemd_macros_package(self) -> &'static str {
+        match self {
+            Dist::El7 | Dist::El8 | Dist::Amzn2 => "systemd",
+            _ => "systemd-rpm-macros",
+        }
+    }
+
+    /// Do this distribution's systemd RPM macros provide
+    /// `%sysusers_create_compat`?
+    pub fn supports_sysusers_compat(self) -> bool {
+        matches!(self, Dist::Fedora | Dist::El9 | Dist::Amzn2023)
+    }
+
+    /// Package providing `useradd` and `groupadd`
+    pub fn shadow_package(self) -> &'static str {
+        match self {
+            Dist::Suse => "shadow",
+            _ => "shadow-utils",
+        }
+    }
+
+    /// Can this distribution's rpm install packages with zstd payloads?
+    pub fn supports_zstd_payload(self) -> bool {
+        !self.is_legacy()
+    }
+
+    /// Can this distribution's `%setup` unpack zstd-compressed sources?
+    pub fn supports_zstd_sources(self) -> bool {
+        !self.is_legacy()
+    }
+}
+
+impl FromStr for Dist {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s.to_lowercase().as_ref() {
+            "fedora" | "fc" => Dist::Fedora,
+            "el7" | "rhel7" | "centos7" => Dist::El7,
+            "el8" | "rhel8" => Dist::El8,
+            "el9" | "rhel9" => Dist::El9,
+            "suse" | "opensuse" | "sles" => Dist::Suse,
+            "amzn2" => Dist::Amzn2,
+            "amzn2023" | "al2023" => Dist::Amzn2023,
+            _ => fail!(
+                ErrorKind::Config,
+                "unknown distribution {:?} (expected fedora, el7, el8, el9, suse, amzn2 or amzn2023)",
+                s
+            ),
+        })
+    }
+}
+
+impl TryFrom<String> for Dist {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Dist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: &[Dist] = &[
+        Dist::Fedora,
+        Dist::El7,
+        Dist::El8,
+        Dist::El9,
+        Dist::Suse,
+        Dist::Amzn2,
+        Dist::Amzn2023,
+    ];
+
+    #[test]
+    fn parse() {
+        for &dist in ALL {
+            assert_eq!(dist.as_str().parse::<Dist>().unwrap(), dist);
+            assert_eq!(dist.to_string(), dist.as_str());
+        }
+
+        assert_eq!("RHEL8".parse::<Dist>().unwrap(), Dist::El8);
+        assert_eq!("centos7".parse::<Dist>().unwrap(), Dist::El7);
+        assert_eq!("sles".parse::<Dist>().unwrap(), Dist::Suse);
+        assert_eq!("al2023".parse::<Dist>().unwrap(), Dist::Amzn2023);
+        assert!("el6".parse::<Dist>().is_err());
+        assert!("".parse::<Dist>().is_err());
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(
+            serde_json::from_str::<Dist>("\"rhel9\"").unwrap(),
+            Dist::El9
+        );
+        assert!(serde_json::from_str::<Dist>("\"debian\"").is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        let legacy: Vec<Dist> = ALL.iter().cloned().filter(|d| d.is_legacy()).collect();
+        assert_eq!(legacy, [Dist::El7, Dist::Amzn2]);
+
+        for &dist in ALL {
+            assert_eq!(dist.supports_zstd_payload(), !dist.is_legacy());
+            assert_eq!(dist.supports_zstd_sources(), !dist.is_legacy());
+        }
+
+        assert_eq!(Dist::El8.dist_tag(), Some(".el8"));
+        assert_eq!(Dist::Fedora.dist_tag(), None);
+        assert_eq!(Dist::Suse.license_style(), LicenseStyle::Spdx);
+        assert_eq!(Dist::El9.license_style(), LicenseStyle::Fedora);
+        assert_eq!(Dist::El8.systemd_macros_package(), "systemd");
+        assert_eq!(Dist::El9.systemd_macros_package(), "systemd-rpm-macros");
+        assert!(Dist::Amzn2023.supports_sysusers_compat());
+        assert!(!Dist::El8.supports_sysusers_compat());
+        assert_eq!(Dist::Suse.shadow_package(), "shadow");
+    }
+}
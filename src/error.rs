@@ -8,6 +8,10 @@ use thiserror::Error;
 /// Kinds of errors
 #[derive(Copy, Clone, Eq, Error, PartialEq, Debug)]
 pub enum ErrorKind {
+    /// Errors running `cargo build` or build hooks
+    #[error("build error")]
+    Build,
+
     /// Error in `Cargo.toml`
     #[error("config error")]
     Config,
@@ -56,6 +60,18 @@ impl ErrorKind {
 #[derive(Debug)]
 pub struct Error(Box<Context<ErrorKind>>);
 
+impl Error {
+    /// Prefix the message of this error with the Cargo.toml key which caused it
+    pub fn with_key(self, key: &str) -> Self {
+        let message = match std::error::Error::source(&self) {
+            Some(source) => source.to_string(),
+            None => self.to_string(),
+        };
+
+        format_err!(*self.kind(), "{}: {}", key, message).into()
+    }
+}
+
 impl Deref for Error {
     type Target = Context<ErrorKind>;
 
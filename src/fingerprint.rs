@@ -0,0 +1,261 @@
+//! Fingerprints of build inputs, used to skip rebuilding unchanged RPMs
+
+use crate::{
+    error::{Error, ErrorKind},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+/// File (within the rpmbuild directory) where the last fingerprint is kept
+pub const FINGERPRINT_FILE: &str = "fingerprint.json";
+
+/// Fingerprint of the inputs to a previous build, and the RPMs it produced
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Fingerprint {
+    /// Hex-encoded SHA-256 digest of the build inputs
+    pub digest: String,
+
+    /// RPMs written by `rpmbuild`
+    pub rpms: Vec<PathBuf>,
+}
+
+impl Fingerprint {
+    /// Load a previously saved fingerprint (if there is a valid one)
+    pub fn load(path: &Path) -> Option<Self> {
+        let json = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    /// Save this fingerprint to the given path
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format_err!(ErrorKind::Parse, "error serializing fingerprint: {}", e))?;
+
+        let mut fingerprint_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        writeln!(fingerprint_file, "{}", json)?;
+        Ok(())
+    }
+
+    /// Do the inputs match this fingerprint, with its RPMs still in place?
+    pub fn is_fresh(&self, digest: &str) -> bool {
+        self.digest == digest && !self.rpms.is_empty() && self.rpms.iter().all(|rpm| rpm.exists())
+    }
+}
+
+/// Computes the digest of a build's inputs
+#[derive(Default)]
+pub struct FingerprintHasher(Sha256);
+
+impl FingerprintHasher {
+    /// Create a new hasher
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a field to the digest. Fields are length-prefixed so that
+    /// adjacent fields can't be confused with each other.
+    pub fn field(&mut self, data: impl AsRef<[u8]>) -> &mut Self {
+        let data = data.as_ref();
+        self.0.update((data.len() as u64).to_le_bytes());
+        self.0.update(data);
+        self
+    }
+
+    /// Compute the hex-encoded digest
+    pub fn finish(self) -> String {
+        format!("{:x}", self.0.finalize())
+    }
+}
+
+/// Compute the SHA-256 digest of the contents of each of the given files,
+/// hashing up to `threads` files at once. Digests are returned in the same
+/// order as the paths, ready to add to a fingerprint with
+/// [`FingerprintHasher::field`].
+pub fn digest_files(paths: Vec<PathBuf>, threads: u32) -> Result<Vec<Vec<u8>>, Error> {
+    let threads = (threads as usize).min(paths.len());
+
+    if threads <= 1 {
+        return paths
+            .iter()
+            .map(|path| digest_file(path).map_err(Error::from))
+            .collect();
+    }
+
+    let paths = Arc::new(paths);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    // Each thread takes the next unhashed file until there are none left
+    let workers: Vec<_> = (0..threads)
+        .map(|_| {
+            let paths = Arc::clone(&paths);
+            let next = Arc::clone(&next);
+            let sender = sender.clone();
+
+            thread::spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+
+                match paths.get(index) {
+                    Some(path) => {
+                        if sender.send((index, digest_file(path))).is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            })
+        })
+        .collect();
+
+    drop(sender);
+
+    let mut digests = vec![vec![]; paths.len()];
+    let mut error = None;
+
+    for (index, digest) in receiver {
+        match digest {
+            Ok(digest) => digests[index] = digest,
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+
+    for worker in workers {
+        worker.join().expect("hashing thread panicked");
+    }
+
+    match error {
+        Some(e) => Err(e.into()),
+        None => Ok(digests),
+    }
+}
+
+/// Compute the SHA-256 digest of a file's contents
+fn digest_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut buffer = [0u8; 8192];
+    let mut file_hasher = Sha256::new();
+
+    loop {
+        let nbytes = file.read(&mut buffer)?;
+
+        if nbytes == 0 {
+            break;
+        }
+
+        file_hasher.update(&buffer[..nbytes]);
+    }
+
+    Ok(file_hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a fresh directory for a test's files
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "cargo-rpm-fingerprint-{}-{}",
+            name,
+            std::process::id()
+        ));
+
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn digest(fields: &[&str]) -> String {
+        let mut hasher = FingerprintHasher::new();
+
+        for field in fields {
+            hasher.field(field);
+        }
+
+        hasher.finish()
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(digest(&["a", "bc"]), digest(&["a", "bc"]));
+        assert_ne!(digest(&["a", "bc"]), digest(&["ab", "c"]));
+        assert_ne!(digest(&["abc"]), digest(&["abc", ""]));
+        assert_eq!(digest(&[]).len(), 64);
+    }
+
+    #[test]
+    fn files() {
+        let dir = temp_dir("files");
+        let paths: Vec<PathBuf> = (0..16)
+            .map(|i| {
+                let path = dir.join(i.to_string());
+                fs::write(&path, i.to_string().repeat(1000)).unwrap();
+                path
+            })
+            .collect();
+
+        let serial = digest_files(paths.clone(), 1).unwrap();
+        assert_eq!(serial[3], digest_file(&paths[3]).unwrap());
+        assert_ne!(serial[3], serial[4]);
+
+        // Threads return the digests in the same order
+        assert_eq!(digest_files(paths.clone(), 4).unwrap(), serial);
+
+        let mut missing = paths;
+        missing.insert(5, dir.join("missing"));
+        assert!(digest_files(missing.clone(), 1).is_err());
+        assert!(digest_files(missing, 4).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn freshness() {
+        let dir = temp_dir("freshness");
+        let rpm = dir.join("foo-1.0.0-1.x86_64.rpm");
+        let path = dir.join(FINGERPRINT_FILE);
+        assert!(Fingerprint::load(&path).is_none());
+
+        Fingerprint {
+            digest: "abc".to_owned(),
+            rpms: vec![rpm.clone()],
+        }
+        .save(&path)
+        .unwrap();
+
+        let fingerprint = Fingerprint::load(&path).unwrap();
+        assert_eq!(fingerprint.rpms, vec![rpm.clone()]);
+
+        // The RPMs it produced have to still be there
+        assert!(!fingerprint.is_fresh("abc"));
+        fs::write(&rpm, b"").unwrap();
+        assert!(fingerprint.is_fresh("abc"));
+        assert!(!fingerprint.is_fresh("abd"));
+
+        fs::write(&path, b"{").unwrap();
+        assert!(Fingerprint::load(&path).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -8,11 +8,19 @@ pub mod application;
 pub mod archive;
 pub mod builder;
 pub mod commands;
+pub mod compression;
 pub mod config;
+pub mod dist;
 pub mod error;
+pub mod fingerprint;
 pub mod license;
 mod prelude;
+pub mod report;
 pub mod rpmbuild;
+pub mod scriptlets;
+pub mod snippets;
+pub mod systemd;
 pub mod target;
 pub mod target_architecture;
 pub mod templates;
+pub mod users;
@@ -1,8 +1,10 @@
 //! Convert Cargo's SPDX 2.1 licenses to the format used by RPM's `License`
-//! field. RPM's valid licenses can be found in the "Short Name" column of
+//! field. Older Fedora-derived distributions use the "Short Name" column of
 //! the "Good Licenses" table from the Licensing page of the Fedora Project Wiki:
 //!
 //! <https://fedoraproject.org/wiki/Licensing:Main>
+//!
+//! while current Fedora releases and SUSE use SPDX expressions.
 
 #![allow(non_camel_case_types)]
 
@@ -13,26 +15,47 @@ use crate::{
 };
 use std::fs;
 
-/// Convert between an SPDX 2.1 license syntax and the RPM `License` field syntax
-pub fn convert(license: &CargoLicense) -> Result<String, Error> {
+/// Styles of RPM `License` field
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LicenseStyle {
+    /// Fedora's (legacy) short names, e.g. `ASL 2.0 or MIT`
+    Fedora,
+
+    /// SPDX expressions, e.g. `Apache-2.0 OR MIT`
+    Spdx,
+}
+
+/// Convert between an SPDX 2.1 license syntax and the RPM `License` field
+/// syntax of the given style
+pub fn convert(license: &CargoLicense, style: LicenseStyle) -> Result<String, Error> {
     let mut output = vec![];
 
+    let (and, or) = match style {
+        LicenseStyle::Fedora => ("and", "or"),
+        LicenseStyle::Spdx => ("AND", "OR"),
+    };
+
+    let license_str = |license: License| match style {
+        LicenseStyle::Fedora => license.as_rpm_str(),
+        LicenseStyle::Spdx => license.as_spdx_str(),
+    };
+
     match license {
         CargoLicense::License(ref s) => {
             if s.find('/').is_some() {
                 // Legacy syntax with '/' delimiter meaning "or"
                 for lic in s.split('/') {
-                    output.push(License::parse(lic)?.as_rpm_str());
+                    output.push(license_str(License::parse(lic)?));
                 }
 
-                Ok(output.as_slice().join(" or "))
+                Ok(output.as_slice().join(&format!(" {} ", or)))
             } else {
                 // Preferred syntax with "and"/"or" keywords
                 for token in s.split_whitespace() {
                     output.push(match token.to_lowercase().as_ref() {
-                        "and" => "and",
-                        "or" => "or",
-                        other => License::parse(other)?.as_rpm_str(),
+                        "and" => and,
+                        "or" => or,
+                        other => license_str(License::parse(other)?),
                     });
                 }
 
@@ -180,4 +203,28 @@ impl License {
             License::MPL_2_0 => "MPLv2.0",
         }
     }
+
+    /// Return the SPDX identifier of a license
+    pub fn as_spdx_str(&self) -> &'static str {
+        match *self {
+            License::AGPL_3_0_ONLY => "AGPL-3.0-only",
+            License::AGPL_3_0_OR_LATER => "AGPL-3.0-or-later",
+            License::APACHE_2_0 => "Apache-2.0",
+            License::BSD_2_CLAUSE => "BSD-2-Clause",
+            License::BSD_3_CLAUSE => "BSD-3-Clause",
+            License::CC0_1_0 => "CC0-1.0",
+            License::GPL_2_0_ONLY => "GPL-2.0-only",
+            License::GPL_2_0_OR_LATER => "GPL-2.0-or-later",
+            License::GPL_3_0_ONLY => "GPL-3.0-only",
+            License::GPL_3_0_OR_LATER => "GPL-3.0-or-later",
+            License::LGPL_2_0_ONLY => "LGPL-2.0-only",
+            License::LGPL_2_0_OR_LATER => "LGPL-2.0-or-later",
+            License::LGPL_2_1_ONLY => "LGPL-2.1-only",
+            License::LGPL_2_1_OR_LATER => "LGPL-2.1-or-later",
+            License::LGPL_3_0_ONLY => "LGPL-3.0-only",
+            License::LGPL_3_0_OR_LATER => "LGPL-3.0-or-later",
+            License::MIT => "MIT",
+            License::MPL_2_0 => "MPL-2.0",
+        }
+    }
 }
@@ -0,0 +1,215 @@
+//! Machine-readable (JSON) reports describing the RPMs produced by a build
+
+use crate::{
+    builder::BuiltRpm,
+    error::{Error, ErrorKind},
+    prelude::*,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Report describing every RPM produced by a `cargo rpm build` invocation
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Version of `cargo` used for the build
+    pub cargo_version: Option<String>,
+
+    /// Version of `rpmbuild` used for the build
+    pub rpmbuild_version: Option<String>,
+
+    /// RPMs produced by the build (one per target)
+    pub rpms: Vec<RpmReport>,
+}
+
+impl Report {
+    /// Create a report from the RPMs produced by the builder
+    pub fn new(built: &[BuiltRpm], rpmbuild_version: Option<String>) -> Result<Self, Error> {
+        let rpms = built
+            .iter()
+            .map(RpmReport::new)
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Self {
+            cargo_version: cargo_version(),
+            rpmbuild_version,
+            rpms,
+        })
+    }
+
+    /// Write this report as JSON to the given path
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format_err!(ErrorKind::Parse, "error serializing report: {}", e))?;
+
+        let mut report_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        writeln!(report_file, "{}", json)?;
+        Ok(())
+    }
+}
+
+/// Report for an RPM built for a single target
+#[derive(Debug, Serialize)]
+pub struct RpmReport {
+    /// Rust target triple (or `None` for the host)
+    pub target: Option<String>,
+
+    /// RPM target architecture
+    pub arch: String,
+
+    /// Name, version, release and architecture of the RPM
+    pub nevra: String,
+
+    /// Binary RPM (if any)
+    pub rpm: Option<Artifact>,
+
+    /// Source RPM (if any)
+    pub srpm: Option<Artifact>,
+
+    /// Time taken by each stage of the build (in seconds)
+    pub stages: BTreeMap<String, f64>,
+
+    /// Total time taken to build the RPM (in seconds)
+    pub elapsed: f64,
+}
+
+impl RpmReport {
+    /// Create a report for a built RPM, computing digests of its artifacts
+    pub fn new(built: &BuiltRpm) -> Result<Self, Error> {
+        let rpm = match built.rpm {
+            Some(ref path) => Some(Artifact::new(path)?),
+            None => None,
+        };
+
+        let srpm = match built.srpm {
+            Some(ref path) => Some(Artifact::new(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            target: built.target.clone(),
+            arch: built.arch.clone(),
+            nevra: built.nevra.clone(),
+            rpm,
+            srpm,
+            stages: built
+                .stages
+                .iter()
+                .map(|(name, duration)| (name.to_string(), duration.as_secs_f64()))
+                .collect(),
+            elapsed: built.elapsed.as_secs_f64(),
+        })
+    }
+}
+
+/// A file produced by `rpmbuild`
+#[derive(Debug, Serialize)]
+pub struct Artifact {
+    /// Path to the file
+    pub path: PathBuf,
+
+    /// Size of the file in bytes
+    pub size: u64,
+
+    /// Hex-encoded SHA-256 digest of the file
+    pub sha256: String,
+}
+
+impl Artifact {
+    /// Compute the size and digest of the file at the given path
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; 8192];
+        let mut size = 0;
+
+        loop {
+            let nbytes = file.read(&mut buffer)?;
+
+            if nbytes == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..nbytes]);
+            size += nbytes as u64;
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+/// Get the version of `cargo` (if it can be determined)
+fn cargo_version() -> Option<String> {
+    let output = Command::new("cargo").arg("--version").output().ok()?;
+
+    if output.status.success() {
+        String::from_utf8(output.stdout)
+            .ok()
+            .map(|vers| vers.trim().to_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::Duration};
+
+    #[test]
+    fn rpm_report() {
+        let dir = std::env::temp_dir().join(format!("cargo-rpm-report-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rpm = dir.join("foo-1.0.0-1.x86_64.rpm");
+        fs::write(&rpm, b"abc").unwrap();
+
+        let built = BuiltRpm {
+            target: None,
+            arch: "x86_64".to_owned(),
+            nevra: "foo-1.0.0-1.x86_64".to_owned(),
+            rpm: Some(rpm.clone()),
+            srpm: None,
+            stages: vec![("archive", Duration::from_millis(500))],
+            elapsed: Duration::from_secs(2),
+        };
+
+        let report = serde_json::to_value(RpmReport::new(&built).unwrap()).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "target": null,
+                "arch": "x86_64",
+                "nevra": "foo-1.0.0-1.x86_64",
+                "rpm": {
+                    "path": rpm,
+                    "size": 3,
+                    "sha256": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                },
+                "srpm": null,
+                "stages": { "archive": 0.5 },
+                "elapsed": 2.0
+            })
+        );
+
+        // Artifacts which have gone missing are reported as errors
+        fs::remove_file(&rpm).unwrap();
+        assert!(RpmReport::new(&built).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -4,18 +4,73 @@ use crate::{
     error::{Error, ErrorKind},
     prelude::*,
 };
+use serde::Deserialize;
 use std::{
+    env,
     ffi::OsStr,
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
     process::{Child, Command, Stdio},
+    str::FromStr,
+    sync::mpsc,
+    thread,
 };
 
 /// Path to the `rpmbuild` command
 pub const DEFAULT_RPMBUILD_PATH: &str = "rpmbuild";
 
-/// Version of rpmbuild supported by this tool
-pub const SUPPORTED_RPMBUILD_VERSION: &str = " 4.";
+/// Path to the `rpm` command, unless `rpmbuild` is given as a path (in which
+/// case the `rpm` alongside it is used)
+pub const DEFAULT_RPM_PATH: &str = "rpm";
+
+/// Environment variable which overrides the path to `rpmbuild`
+pub const RPMBUILD_ENV_VAR: &str = "RPMBUILD";
+
+/// Oldest version of rpmbuild supported by this tool (major, minor)
+pub const MINIMUM_RPMBUILD_VERSION: (u32, u32) = (4, 4);
+
+/// An rpmbuild version as (major, minor, patch)
+type VersionTriple = (u32, u32, u32);
+
+/// rpmbuild versions (first and last, inclusive) which cargo-rpm can't work
+/// with despite being new enough, and why
+const KNOWN_BAD_RPMBUILD_VERSIONS: &[(VersionTriple, VersionTriple, &str)] = &[(
+    (5, 0, 0),
+    (5, u32::MAX, u32::MAX),
+    "rpm 5.x is the unmaintained rpm5.org fork rather than rpm.org's rpm \
+     (whose releases go from 4.x to 6.x), and isn't supported",
+)];
+
+/// Name of the file (in the rpmbuild directory) rpmbuild's output is logged to
+pub const BUILD_LOG_FILE: &str = "build.log";
+
+/// Number of lines from the end of the build log to display on failure
+const LOG_TAIL_LINES: usize = 30;
+
+/// Common rpmbuild failures and hints about how to resolve them
+const FAILURE_HINTS: &[(&str, &str)] = &[
+    (
+        "Installed (but unpackaged) file(s) found",
+        "files in the archive aren't listed in the spec's %files section; \
+         add them (or the @@TARGETS@@ placeholder) to %files in the .spec, \
+         or remove them from [package.metadata.rpm]",
+    ),
+    (
+        "File not found",
+        "a path in the spec's %files section isn't in the archive; \
+         check it matches a `path` in [package.metadata.rpm.targets] or [package.metadata.rpm.files]",
+    ),
+    (
+        "File must begin with \"/\"",
+        "%files entries must be absolute paths (or start with a macro like %{_bindir})",
+    ),
+    (
+        "Failed build dependencies",
+        "install the packages listed under BuildRequires (e.g. `dnf builddep <spec>`)",
+    ),
+];
 
 /// Wrapper for the `rpmbuild` command
 pub struct Rpmbuild {
@@ -27,75 +82,175 @@ pub struct Rpmbuild {
 }
 
 impl Rpmbuild {
-    /// Prepare `rpmbuild`, checking the correct version is installed
-    pub fn new(verbose: bool) -> Result<Self, Error> {
-        let rpmbuild = Self {
-            path: DEFAULT_RPMBUILD_PATH.into(),
-            verbose,
+    /// Prepare `rpmbuild`, checking the correct version is installed.
+    ///
+    /// The path to `rpmbuild` is taken from `$RPMBUILD` if set, otherwise from
+    /// the given path (i.e. from `Cargo.toml`), or `rpmbuild` in `$PATH`.
+    pub fn new(path: Option<&Path>, verbose: bool) -> Result<Self, Error> {
+        let path = match env::var_os(RPMBUILD_ENV_VAR) {
+            Some(p) => PathBuf::from(p),
+            None => path
+                .unwrap_or_else(|| Path::new(DEFAULT_RPMBUILD_PATH))
+                .to_owned(),
         };
 
+        let rpmbuild = Self { path, verbose };
+
         // Make sure we have a valid version of rpmbuild
         rpmbuild.version()?;
         Ok(rpmbuild)
     }
 
-    /// Get version of `rpmbuild`
-    pub fn version(&self) -> Result<String, Error> {
-        let output = Command::new(&self.path)
-            .args(&["--version"])
+    /// Get version of `rpmbuild`, failing if it's unsupported
+    pub fn version(&self) -> Result<RpmbuildVersion, Error> {
+        let output = self.run(&["--version"])?;
+        let version = RpmbuildVersion::parse(&output)?;
+
+        if (version.major, version.minor) < MINIMUM_RPMBUILD_VERSION {
+            fail!(
+                ErrorKind::Rpmbuild,
+                "rpmbuild {} is too old (cargo-rpm requires rpmbuild {}.{} or newer)",
+                version,
+                MINIMUM_RPMBUILD_VERSION.0,
+                MINIMUM_RPMBUILD_VERSION.1
+            );
+        }
+
+        if let Some(reason) = version.known_problem() {
+            fail!(
+                ErrorKind::Rpmbuild,
+                "rpmbuild {} can't be used: {}",
+                version,
+                reason
+            );
+        }
+
+        Ok(version)
+    }
+
+    /// Probe which optional features this `rpmbuild` supports. Features
+    /// which only some versions have are detected here rather than inferred
+    /// from the version, since distributions backport them.
+    pub fn capabilities(&self) -> Result<Capabilities, Error> {
+        let showrc = self.run(&["--showrc"])?;
+
+        Ok(Capabilities {
+            build_name_fmt: self.eval("%{?_build_name_fmt:1}")? == "1",
+            zstd_payload: showrc.contains("rpmlib(PayloadIsZstd)"),
+        })
+    }
+
+    /// Evaluate an rpm macro expression, e.g. `%{_arch}`
+    pub fn eval(&self, expr: &str) -> Result<String, Error> {
+        self.eval_with(&[], expr)
+    }
+
+    /// Evaluate an rpm macro expression after defining the given macros
+    /// (each given as `name value`, as with `-D`)
+    pub fn eval_with(&self, defines: &[String], expr: &str) -> Result<String, Error> {
+        let mut args = vec![];
+
+        for define in defines {
+            args.extend(&["-D", define.as_str()]);
+        }
+
+        args.extend(&["--eval", expr]);
+        Ok(self.run(&args)?.trim().to_owned())
+    }
+
+    /// Query the name-(epoch:)version-release.arch of a package rpmbuild
+    /// wrote. Source RPMs are reported with the `src` architecture, as rpm
+    /// itself names them.
+    pub fn query_nevra(&self, package: &Path) -> Result<String, Error> {
+        let rpm_path = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.join(DEFAULT_RPM_PATH),
+            _ => PathBuf::from(DEFAULT_RPM_PATH),
+        };
+
+        // Only binary packages record the source RPM they were built from.
+        // `%{NEVRA}` isn't understood by older rpms, so spell it out.
+        let query_format =
+            "%{NAME}-%|EPOCH?{%{EPOCH}:}|%{VERSION}-%{RELEASE}.%|SOURCERPM?{%{ARCH}}:{src}|";
+        let output = Command::new(&rpm_path)
+            .arg("-qp")
+            .arg("--qf")
+            .arg(query_format)
+            .arg(package)
             .output()
             .map_err(|e| {
                 format_err!(
                     ErrorKind::Rpmbuild,
                     "error running {}: {}",
-                    self.path.display(),
+                    rpm_path.display(),
                     e
                 )
             })?;
 
-        if !output.status.success() {
+        let nevra = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+        if !output.status.success() || nevra.is_empty() {
             fail!(
                 ErrorKind::Rpmbuild,
-                "error running {} (exit status: {})",
-                &self.path.display(),
-                &output.status
+                "error querying {} with {} (exit status: {})",
+                package.display(),
+                rpm_path.display(),
+                output.status
             );
         }
 
-        let vers = String::from_utf8(output.stdout).map_err(|e| {
+        Ok(nevra)
+    }
+
+    /// Run `rpmbuild` with the given arguments, returning its stdout
+    fn run(&self, args: &[&str]) -> Result<String, Error> {
+        let output = Command::new(&self.path).args(args).output().map_err(|e| {
             format_err!(
                 ErrorKind::Rpmbuild,
-                "error parsing rpmbuild output as UTF-8: {}",
+                "error running {}: {}",
+                self.path.display(),
                 e
             )
         })?;
 
-        if !vers.contains(SUPPORTED_RPMBUILD_VERSION) {
+        if !output.status.success() {
             fail!(
                 ErrorKind::Rpmbuild,
-                "unexpected rpmbuild version string: {:?}",
-                vers
+                "error running {} {} (exit status: {})",
+                self.path.display(),
+                args.join(" "),
+                output.status
             );
         }
 
-        let parts: Vec<&str> = vers.split_whitespace().collect();
-        Ok(parts[parts.len() - 1].to_owned())
+        String::from_utf8(output.stdout).map_err(|e| {
+            format_err!(
+                ErrorKind::Rpmbuild,
+                "error parsing rpmbuild output as UTF-8: {}",
+                e
+            )
+            .into()
+        })
     }
 
-    /// Execute `rpmbuild` with the given arguments
-    pub fn exec<I, S>(&self, args: I) -> Result<(), Error>
+    /// Execute `rpmbuild` with the given arguments in the given directory,
+    /// returning its stdout.
+    ///
+    /// Both stdout and stderr are captured (interleaved) into a `build.log`
+    /// file in the given directory. If rpmbuild fails, the tail of the log is
+    /// displayed along with hints for any failures we recognize.
+    pub fn exec<I, S>(&self, dir: &Path, args: I) -> Result<String, Error>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
     {
+        let log_path = dir.join(BUILD_LOG_FILE);
+        let mut log = File::create(&log_path)?;
+
         let mut rpmbuild = Command::new(&self.path)
+            .current_dir(dir)
             .args(args)
             .stdout(Stdio::piped())
-            .stderr(if self.verbose {
-                Stdio::inherit()
-            } else {
-                Stdio::null()
-            })
+            .stderr(Stdio::piped())
             .spawn()
             .map_err(|e| {
                 format_err!(
@@ -106,37 +261,298 @@ impl Rpmbuild {
                 )
             })?;
 
-        let output = self.read_rpmbuild_output(&mut rpmbuild)?;
+        // Wait for rpmbuild even if reading its output failed, so it's reaped
+        let output = self.read_rpmbuild_output(&mut rpmbuild, &mut log);
         let status = rpmbuild.wait()?;
+        let output = output?;
 
         if status.success() {
-            Ok(())
+            Ok(output.stdout)
         } else {
             if !self.verbose {
-                eprintln!("{}", &output);
+                let tail_start = output.log.len().saturating_sub(LOG_TAIL_LINES);
+
+                for line in &output.log[tail_start..] {
+                    eprintln!("{}", line);
+                }
+            }
+
+            for (pattern, hint) in FAILURE_HINTS {
+                if output.log.iter().any(|line| line.contains(pattern)) {
+                    status_warn!("{}: {}", pattern, hint);
+                }
             }
 
             fail!(
                 ErrorKind::Rpmbuild,
-                "error running {} (exit status: {})",
+                "error running {} (exit status: {}, see {})",
                 self.path.display(),
-                status
+                status,
+                log_path.display()
             );
         }
     }
 
-    /// Read stdout from rpmbuild, either displaying it or discarding it
-    fn read_rpmbuild_output(&self, subprocess: &mut Child) -> Result<String, Error> {
-        let mut reader = BufReader::new(subprocess.stdout.as_mut().unwrap());
-        let mut string = String::new();
+    /// Read stdout and stderr from rpmbuild, writing them to the build log
+    /// and displaying them in verbose mode. Both streams are read to the end
+    /// even if one fails, so rpmbuild never blocks writing to a full pipe.
+    fn read_rpmbuild_output(
+        &self,
+        subprocess: &mut Child,
+        log: &mut File,
+    ) -> Result<RpmbuildOutput, Error> {
+        let (sender, receiver) = mpsc::channel();
+
+        let stdout = subprocess.stdout.take().unwrap();
+        let stdout_sender = sender.clone();
+        let stdout_reader = thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if stdout_sender.send(line.map(OutputLine::Stdout)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stderr = subprocess.stderr.take().unwrap();
+        let stderr_reader = thread::spawn(move || {
+            for line in BufReader::new(stderr).lines() {
+                if sender.send(line.map(OutputLine::Stderr)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut output = RpmbuildOutput::default();
+        let mut error = None;
+
+        // Lines from the two streams arrive approximately interleaved, as
+        // each is read on its own thread
+        for line in receiver {
+            if error.is_some() {
+                continue;
+            }
+
+            let result = line.map_err(Error::from).and_then(|line| match line {
+                OutputLine::Stdout(line) => {
+                    if self.verbose {
+                        status_ok!("rpmbuild", line.trim_end());
+                    }
 
-        while reader.read_line(&mut string)? != 0 {
-            if self.verbose {
-                status_ok!("rpmbuild", string.trim_end());
-                string.clear();
+                    output.stdout.push_str(&line);
+                    output.stdout.push('\n');
+                    writeln!(log, "{}", line)?;
+                    output.log.push(line);
+                    Ok(())
+                }
+                OutputLine::Stderr(line) => {
+                    if self.verbose {
+                        eprintln!("{}", line);
+                    }
+
+                    writeln!(log, "{}", line)?;
+                    output.log.push(line);
+                    Ok(())
+                }
+            });
+
+            if let Err(e) = result {
+                error = Some(e);
             }
         }
 
-        Ok(string)
+        stdout_reader.join().expect("stdout reader panicked");
+        stderr_reader.join().expect("stderr reader panicked");
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(output),
+        }
+    }
+}
+
+/// Which packages `rpmbuild` should build
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildMode {
+    /// Binary RPM only (`-bb`)
+    Bin,
+
+    /// Source RPM only (`-bs`)
+    Src,
+
+    /// Both binary and source RPMs (`-ba`)
+    All,
+}
+
+impl BuildMode {
+    /// Get the `rpmbuild` flag for this mode
+    pub fn as_flag(self) -> &'static str {
+        match self {
+            BuildMode::Bin => "-bb",
+            BuildMode::Src => "-bs",
+            BuildMode::All => "-ba",
+        }
+    }
+}
+
+impl FromStr for BuildMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "bin" => BuildMode::Bin,
+            "src" => BuildMode::Src,
+            "all" => BuildMode::All,
+            _ => fail!(
+                ErrorKind::Parse,
+                "unknown rpmbuild mode {:?} (expected bin, src or all)",
+                s
+            ),
+        })
+    }
+}
+
+/// Version of `rpmbuild`
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct RpmbuildVersion {
+    /// Major version
+    pub major: u32,
+
+    /// Minor version
+    pub minor: u32,
+
+    /// Patch version
+    pub patch: u32,
+}
+
+impl RpmbuildVersion {
+    /// Parse the output of `rpmbuild --version`, e.g. `RPM version 4.16.1.3`
+    /// or `rpmbuild (RPM) 5.4.14`
+    pub fn parse(version_string: &str) -> Result<Self, Error> {
+        let parse_error = || {
+            format_err!(
+                ErrorKind::Rpmbuild,
+                "couldn't parse rpmbuild version string: {:?}",
+                version_string
+            )
+        };
+
+        let version = version_string
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().last())
+            .ok_or_else(parse_error)?;
+
+        // Components may have suffixes, e.g. `4.17.0-alpha`
+        let mut components = version.split('.').map(|component| {
+            let digits: String = component.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u32>()
+        });
+
+        let major = components
+            .next()
+            .and_then(Result::ok)
+            .ok_or_else(parse_error)?;
+        let minor = components.next().and_then(Result::ok).unwrap_or(0);
+        let patch = components.next().and_then(Result::ok).unwrap_or(0);
+
+        Ok(Self {
+            major,
+            minor,
+            patch,
+        })
+    }
+
+    /// Why this version is known not to work with cargo-rpm (if it is)
+    pub fn known_problem(&self) -> Option<&'static str> {
+        let version = (self.major, self.minor, self.patch);
+
+        KNOWN_BAD_RPMBUILD_VERSIONS
+            .iter()
+            .find(|(first, last, _)| *first <= version && version <= *last)
+            .map(|(_, _, reason)| *reason)
+    }
+}
+
+impl fmt::Display for RpmbuildVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Optional features supported by a particular `rpmbuild`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Capabilities {
+    /// Is the output filename configurable via `%_build_name_fmt`?
+    pub build_name_fmt: bool,
+
+    /// Can rpmbuild produce zstd-compressed payloads?
+    pub zstd_payload: bool,
+}
+
+/// A line of output from rpmbuild
+enum OutputLine {
+    /// Line written to stdout
+    Stdout(String),
+
+    /// Line written to stderr
+    Stderr(String),
+}
+
+/// Output captured from an rpmbuild invocation
+#[derive(Default)]
+struct RpmbuildOutput {
+    /// Everything rpmbuild wrote to stdout
+    stdout: String,
+
+    /// Every line rpmbuild wrote to either stream (in order)
+    log: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RpmbuildVersion;
+
+    fn parse(version_string: &str) -> (u32, u32, u32) {
+        let version = RpmbuildVersion::parse(version_string).unwrap();
+        (version.major, version.minor, version.patch)
+    }
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!(parse("RPM version 4.16.1.3\n"), (4, 16, 1));
+        assert_eq!(parse("rpmbuild (RPM) 5.4.14"), (5, 4, 14));
+        assert_eq!(parse("RPM version 4.20.0-alpha"), (4, 20, 0));
+        assert_eq!(parse("6.0.0"), (6, 0, 0));
+        assert_eq!(parse("RPM version 4.18"), (4, 18, 0));
+    }
+
+    #[test]
+    fn parse_garbage() {
+        assert!(RpmbuildVersion::parse("").is_err());
+        assert!(RpmbuildVersion::parse("rpmbuild: command not found").is_err());
+        assert!(RpmbuildVersion::parse("RPM version alpha").is_err());
+    }
+
+    #[test]
+    fn known_problems() {
+        let problem = |version| RpmbuildVersion::parse(version).unwrap().known_problem();
+
+        assert!(problem("RPM version 4.4.2").is_none());
+        assert!(problem("RPM version 4.20.1").is_none());
+        assert!(problem("rpmbuild (RPM) 5.4.14")
+            .unwrap()
+            .contains("rpm5.org"));
+        assert!(problem("RPM version 6.0.0").is_none());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            RpmbuildVersion::parse("RPM version 4.16.1.3")
+                .unwrap()
+                .to_string(),
+            "4.16.1"
+        );
     }
 }
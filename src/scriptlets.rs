@@ -0,0 +1,63 @@
+//! Dependencies and scriptlets which are added to the spec for files
+//! generated from `Cargo.toml` (e.g. systemd units)
+
+/// Spec preamble lines and scriptlet sections, which are substituted for the
+/// `@@REQUIRES@@` and `@@SCRIPTLETS@@` placeholders
+#[derive(Debug, Default)]
+pub struct Scriptlets {
+    /// Preamble lines, e.g. `Requires(post): systemd`
+    pub preamble: Vec<String>,
+
+    /// Lines of the `%pre` scriptlet
+    pub pre: Vec<String>,
+
+    /// Lines of the `%post` scriptlet
+    pub post: Vec<String>,
+
+    /// Lines of the `%preun` scriptlet
+    pub preun: Vec<String>,
+
+    /// Lines of the `%postun` scriptlet
+    pub postun: Vec<String>,
+}
+
+impl Scriptlets {
+    /// Add a line to the preamble, unless it's already there
+    pub fn add_preamble(&mut self, line: impl Into<String>) {
+        let line = line.into();
+
+        if !self.preamble.contains(&line) {
+            self.preamble.push(line);
+        }
+    }
+
+    /// Are there any scriptlet sections?
+    pub fn has_sections(&self) -> bool {
+        !(self.pre.is_empty()
+            && self.post.is_empty()
+            && self.preun.is_empty()
+            && self.postun.is_empty())
+    }
+
+    /// Render the preamble lines
+    pub fn render_preamble(&self) -> String {
+        self.preamble.join("\n")
+    }
+
+    /// Render the non-empty scriptlet sections
+    pub fn render_sections(&self) -> String {
+        let sections = [
+            ("%pre", &self.pre),
+            ("%post", &self.post),
+            ("%preun", &self.preun),
+            ("%postun", &self.postun),
+        ];
+
+        sections
+            .iter()
+            .filter(|(_, lines)| !lines.is_empty())
+            .map(|(name, lines)| format!("{}\n{}\n", name, lines.join("\n")))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
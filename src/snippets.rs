@@ -0,0 +1,476 @@
+//! tmpfiles.d, logrotate and profile.d snippets generated from
+//! `[package.metadata.rpm.tmpfiles]`, `[package.metadata.rpm.logrotate]` and
+//! `[package.metadata.rpm.profile_d]`
+
+use crate::{
+    archive::{config_key, parse_mode, GeneratedFile},
+    config::{LogrotateConfig, PackageConfig, ProfileConfig, TmpfileConfig},
+    dist::Dist,
+    error::{Error, ErrorKind},
+    prelude::*,
+    scriptlets::Scriptlets,
+    systemd,
+};
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Directory in which tmpfiles.d snippets reside
+pub const TMPFILES_DIR: &str = "/usr/lib/tmpfiles.d";
+
+/// Directory in which logrotate policies reside
+pub const LOGROTATE_DIR: &str = "/etc/logrotate.d";
+
+/// Directory in which login shell scripts reside
+pub const PROFILE_DIR: &str = "/etc/profile.d";
+
+/// Permissions of generated snippets
+const SNIPPET_MODE: u32 = 0o644;
+
+/// Types of tmpfiles.d entries
+const TMPFILE_TYPES: &str = "fwdDevqQpLcbCxXrRzZtThHaA";
+
+/// Modifiers which may follow the type of a tmpfiles.d entry
+const TMPFILE_MODIFIERS: &str = "+!-=~^";
+
+/// How often logrotate can rotate logs
+const LOGROTATE_FREQUENCIES: &[&str] = &["daily", "weekly", "monthly", "yearly"];
+
+/// Write the tmpfiles.d, logrotate and profile.d snippets configured in
+/// Cargo.toml into `output_dir`, adding the scriptlet which applies the
+/// tmpfiles.d snippet on install
+pub fn generate_snippets(
+    package: &PackageConfig,
+    output_dir: &Path,
+    dist: Option<Dist>,
+    scriptlets: &mut Scriptlets,
+) -> Result<Vec<GeneratedFile>, Error> {
+    let rpm_metadata = match package.rpm_metadata() {
+        Some(rpm_metadata) => rpm_metadata,
+        None => return Ok(vec![]),
+    };
+
+    let name = package.rpm_name();
+    let mut generated = vec![];
+
+    if let Some(tmpfiles) = non_empty(&rpm_metadata.tmpfiles) {
+        let install_path = Path::new(TMPFILES_DIR).join(format!("{}.conf", name));
+        let src_path = output_dir.join("tmpfiles.conf");
+        fs::write(&src_path, render_tmpfiles(name, tmpfiles)?)?;
+
+        let macros_package = dist.map(Dist::systemd_macros_package).unwrap_or("systemd");
+        scriptlets.add_preamble(format!("BuildRequires: {}", macros_package));
+        scriptlets.add_preamble("Requires(post): systemd");
+        scriptlets
+            .post
+            .push(format!("%tmpfiles_create {}", install_path.display()));
+
+        generated.push(GeneratedFile {
+            src_path,
+            install_path,
+            config_key: config_key("tmpfiles", tmpfiles.keys().next().unwrap()),
+            mode: SNIPPET_MODE,
+            noreplace: false,
+        });
+    }
+
+    if let Some(policies) = non_empty(&rpm_metadata.logrotate) {
+        let src_path = output_dir.join("logrotate");
+        fs::write(&src_path, render_logrotate(name, policies)?)?;
+
+        generated.push(GeneratedFile {
+            src_path,
+            install_path: Path::new(LOGROTATE_DIR).join(name),
+            config_key: config_key("logrotate", policies.keys().next().unwrap()),
+            mode: SNIPPET_MODE,
+            noreplace: true,
+        });
+    }
+
+    if let Some(ref profile) = rpm_metadata.profile_d {
+        let key = "package.metadata.rpm.profile_d";
+        let src_path = output_dir.join("profile.sh");
+        fs::write(
+            &src_path,
+            render_profile(name, profile).map_err(|e| e.with_key(key))?,
+        )?;
+
+        generated.push(GeneratedFile {
+            src_path,
+            install_path: Path::new(PROFILE_DIR).join(format!("{}.sh", name)),
+            config_key: key.to_owned(),
+            mode: SNIPPET_MODE,
+            noreplace: true,
+        });
+    }
+
+    Ok(generated)
+}
+
+/// The given table, unless it's missing or empty
+fn non_empty<T>(table: &Option<BTreeMap<String, T>>) -> Option<&BTreeMap<String, T>> {
+    table.as_ref().filter(|table| !table.is_empty())
+}
+
+/// Check that a path is absolute and has no whitespace, which would split
+/// it in the snippets
+fn check_path(path: &str) -> Result<(), Error> {
+    if !path.starts_with('/') || path.contains(char::is_whitespace) {
+        fail!(
+            ErrorKind::Config,
+            "expected an absolute path without whitespace, not {:?}",
+            path
+        );
+    }
+
+    Ok(())
+}
+
+/// Check that a field of a snippet has no whitespace, which would split it
+fn check_field(option: &str, value: &str) -> Result<(), Error> {
+    if value.is_empty() || value.contains(char::is_whitespace) {
+        fail!(
+            ErrorKind::Config,
+            "`{}` must be non-empty and have no whitespace: {:?}",
+            option,
+            value
+        );
+    }
+
+    Ok(())
+}
+
+/// Render a tmpfiles.d snippet
+fn render_tmpfiles(
+    name: &str,
+    tmpfiles: &BTreeMap<String, TmpfileConfig>,
+) -> Result<String, Error> {
+    let mut contents = format!("# Runtime files and directories of {}\n", name);
+
+    for (path, entry) in tmpfiles {
+        let key = config_key("tmpfiles", path);
+        let line = tmpfiles_line(path, entry).map_err(|e| e.with_key(&key))?;
+        contents.push_str(&line);
+    }
+
+    Ok(contents)
+}
+
+/// Render a line of a tmpfiles.d snippet:
+/// `type path mode user group age [argument]`
+fn tmpfiles_line(path: &str, entry: &TmpfileConfig) -> Result<String, Error> {
+    check_path(path)?;
+
+    let entry_type = entry.entry_type.as_deref().unwrap_or("d");
+    let mut chars = entry_type.chars();
+    let valid = matches!(chars.next(), Some(c) if TMPFILE_TYPES.contains(c))
+        && chars.all(|c| TMPFILE_MODIFIERS.contains(c));
+
+    if !valid {
+        fail!(
+            ErrorKind::Config,
+            "unknown tmpfiles.d entry type {:?} (see tmpfiles.d(5))",
+            entry_type
+        );
+    }
+
+    let mode = match entry.mode {
+        Some(ref mode) => format!("{:04o}", parse_mode(mode, &"the tmpfiles.d entry")?),
+        None => "-".to_owned(),
+    };
+
+    let mut fields = vec![entry_type.to_owned(), path.to_owned(), mode];
+
+    for (option, value) in &[
+        ("user", &entry.user),
+        ("group", &entry.group),
+        ("age", &entry.age),
+    ] {
+        fields.push(match value {
+            Some(value) => {
+                check_field(option, value)?;
+                value.clone()
+            }
+            None => "-".to_owned(),
+        });
+    }
+
+    if let Some(ref argument) = entry.argument {
+        if argument.contains('\n') {
+            fail!(ErrorKind::Config, "`argument` contains a newline");
+        }
+
+        fields.push(argument.clone());
+    }
+
+    Ok(format!("{}\n", fields.join(" ")))
+}
+
+/// Render a logrotate policy with a block for each set of logs
+fn render_logrotate(
+    name: &str,
+    policies: &BTreeMap<String, LogrotateConfig>,
+) -> Result<String, Error> {
+    let mut contents = format!("# Log rotation of {}\n", name);
+
+    for (path, policy) in policies {
+        let key = config_key("logrotate", path);
+        let block = logrotate_block(path, policy).map_err(|e| e.with_key(&key))?;
+        contents.push_str(&format!("\n{}", block));
+    }
+
+    Ok(contents)
+}
+
+/// Render the block of a logrotate policy for a set of logs
+fn logrotate_block(path: &str, policy: &LogrotateConfig) -> Result<String, Error> {
+    check_path(path)?;
+
+    let frequency = policy.frequency.as_deref().unwrap_or("weekly");
+
+    if !LOGROTATE_FREQUENCIES.contains(&frequency) {
+        fail!(
+            ErrorKind::Config,
+            "unknown frequency {:?} (expected one of: {})",
+            frequency,
+            LOGROTATE_FREQUENCIES.join(", ")
+        );
+    }
+
+    let mut directives = vec![
+        frequency.to_owned(),
+        format!("rotate {}", policy.rotate.unwrap_or(4)),
+        "missingok".to_owned(),
+        "notifempty".to_owned(),
+    ];
+
+    if let Some(ref size) = policy.size {
+        check_field("size", size)?;
+        directives.push(format!("maxsize {}", size));
+    }
+
+    if policy.compress.unwrap_or(true) {
+        directives.push("compress".to_owned());
+        directives.push("delaycompress".to_owned());
+    }
+
+    if policy.copytruncate.unwrap_or(false) {
+        directives.push("copytruncate".to_owned());
+    }
+
+    for (directive, value) in &[("create", &policy.create), ("su", &policy.su)] {
+        if let Some(value) = value {
+            if value.contains('\n') {
+                fail!(ErrorKind::Config, "`{}` contains a newline", directive);
+            }
+
+            directives.push(format!("{} {}", directive, value));
+        }
+    }
+
+    let postrotate = policy.postrotate.clone().unwrap_or_default();
+
+    if !postrotate.is_empty() {
+        // Run the commands once, however many logs the path matches
+        directives.push("sharedscripts".to_owned());
+        directives.push("postrotate".to_owned());
+        directives.extend(postrotate.iter().map(|command| format!("    {}", command)));
+        directives.push("endscript".to_owned());
+    }
+
+    let mut block = format!("{} {{\n", path);
+
+    for directive in directives {
+        block.push_str(&format!("    {}\n", directive));
+    }
+
+    block.push_str("}\n");
+    Ok(block)
+}
+
+/// Render a profile.d script which sets up the environment of login shells
+fn render_profile(name: &str, profile: &ProfileConfig) -> Result<String, Error> {
+    let mut contents = format!("# Login shell environment of {}\n", name);
+
+    for (variable, value) in profile.environment.iter().flatten() {
+        systemd::check_variable(variable, value)?;
+
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "\\$")
+            .replace('`', "\\`");
+
+        contents.push_str(&format!("export {}=\"{}\"\n", variable, value));
+    }
+
+    for dir in profile.path.iter().flatten() {
+        let dir_str = dir.to_string_lossy();
+        let valid = dir.is_absolute()
+            && dir_str
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "/._+-".contains(c));
+
+        if !valid {
+            fail!(
+                ErrorKind::Config,
+                "`path` entries must be absolute and only contain letters, digits and `/._+-`: {:?}",
+                dir_str
+            );
+        }
+
+        // Don't add the directory again in nested login shells
+        contents.push_str(&format!(
+            "\ncase \":${{PATH}}:\" in\n    *:{0}:*) ;;\n    *) PATH=\"{0}:${{PATH}}\" ;;\nesac\n",
+            dir_str
+        ));
+    }
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn package() -> PackageConfig {
+        serde_json::from_str(
+            r#"{
+                "name": "foo",
+                "version": "1.0.0",
+                "description": "Foo daemon",
+                "license": "MIT",
+                "metadata": {
+                    "rpm": {
+                        "targets": { "foo": { "path": "/usr/bin/foo" } },
+                        "tmpfiles": {
+                            "/run/foo": { "mode": "750", "user": "foo", "group": "foo" },
+                            "/var/cache/foo": { "age": "10d" }
+                        },
+                        "logrotate": {
+                            "/var/log/foo/*.log": {
+                                "frequency": "daily",
+                                "create": "0640 foo foo",
+                                "postrotate": ["systemctl kill -s HUP foo.service"]
+                            }
+                        },
+                        "profile_d": {
+                            "path": ["/opt/foo/bin"],
+                            "environment": { "FOO_HOME": "/opt/$foo" }
+                        }
+                    }
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    /// Generate the snippets into a fresh directory, returning the install
+    /// path, contents and `noreplace` flag of each
+    fn generate(dist: Option<Dist>, scriptlets: &mut Scriptlets) -> Vec<(PathBuf, String, bool)> {
+        let dir = std::env::temp_dir().join(format!("cargo-rpm-snippets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let generated = generate_snippets(&package(), &dir, dist, scriptlets)
+            .unwrap()
+            .into_iter()
+            .map(|file| {
+                let contents = fs::read_to_string(&file.src_path).unwrap();
+                (file.install_path, contents, file.noreplace)
+            })
+            .collect();
+
+        fs::remove_dir_all(dir).unwrap();
+        generated
+    }
+
+    #[test]
+    fn snippets() {
+        let mut scriptlets = Scriptlets::default();
+        let generated = generate(Some(Dist::El9), &mut scriptlets);
+        assert_eq!(generated.len(), 3);
+
+        let (ref path, ref tmpfiles, noreplace) = generated[0];
+        assert_eq!(path, Path::new("/usr/lib/tmpfiles.d/foo.conf"));
+        assert_eq!(
+            tmpfiles,
+            "# Runtime files and directories of foo\n\
+             d /run/foo 0750 foo foo -\n\
+             d /var/cache/foo - - - 10d\n"
+        );
+        assert!(!noreplace);
+
+        let (ref path, ref logrotate, noreplace) = generated[1];
+        assert_eq!(path, Path::new("/etc/logrotate.d/foo"));
+        assert_eq!(
+            logrotate,
+            "# Log rotation of foo\n\
+             \n\
+             /var/log/foo/*.log {\n    \
+                 daily\n    \
+                 rotate 4\n    \
+                 missingok\n    \
+                 notifempty\n    \
+                 compress\n    \
+                 delaycompress\n    \
+                 create 0640 foo foo\n    \
+                 sharedscripts\n    \
+                 postrotate\n        \
+                 systemctl kill -s HUP foo.service\n    \
+                 endscript\n\
+             }\n"
+        );
+        assert!(noreplace);
+
+        let (ref path, ref profile, noreplace) = generated[2];
+        assert_eq!(path, Path::new("/etc/profile.d/foo.sh"));
+        assert!(profile.contains("export FOO_HOME=\"/opt/\\$foo\"\n"));
+        assert!(profile.contains("*) PATH=\"/opt/foo/bin:${PATH}\" ;;\n"));
+        assert!(noreplace);
+
+        // The tmpfiles.d snippet is applied on install
+        assert_eq!(
+            scriptlets.preamble,
+            [
+                "BuildRequires: systemd-rpm-macros",
+                "Requires(post): systemd"
+            ]
+        );
+        assert_eq!(
+            scriptlets.post,
+            ["%tmpfiles_create /usr/lib/tmpfiles.d/foo.conf"]
+        );
+    }
+
+    #[test]
+    fn tmpfiles_lines() {
+        let entry = |entry_type: &str| TmpfileConfig {
+            entry_type: Some(entry_type.to_owned()),
+            argument: Some("/opt/foo".to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            tmpfiles_line("/var/lib/foo/current", &entry("L+")).unwrap(),
+            "L+ /var/lib/foo/current - - - - /opt/foo\n"
+        );
+        assert!(tmpfiles_line("/run/foo", &entry("y")).is_err());
+        assert!(tmpfiles_line("/run/foo", &entry("d?")).is_err());
+        assert!(tmpfiles_line("run/foo", &entry("d")).is_err());
+        assert!(tmpfiles_line("/run/my foo", &entry("d")).is_err());
+
+        let spaced = TmpfileConfig {
+            user: Some("foo bar".to_owned()),
+            ..Default::default()
+        };
+        assert!(tmpfiles_line("/run/foo", &spaced).is_err());
+    }
+
+    #[test]
+    fn logrotate_frequencies() {
+        let policy = LogrotateConfig {
+            frequency: Some("hourly".to_owned()),
+            ..Default::default()
+        };
+        assert!(logrotate_block("/var/log/foo.log", &policy).is_err());
+    }
+}
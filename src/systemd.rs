@@ -0,0 +1,689 @@
+//! systemd units generated from `[package.metadata.rpm.systemd]`
+
+use crate::{
+    archive::{config_key, GeneratedFile},
+    config::{PackageConfig, RpmConfig, UnitConfig},
+    dist::Dist,
+    error::{Error, ErrorKind},
+    prelude::*,
+    scriptlets::Scriptlets,
+    templates::{ServiceParams, SocketParams, TimerParams},
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Directory in which systemd unit configs reside
+pub const UNIT_DIR: &str = "/usr/lib/systemd/system";
+
+/// Permissions of unit files
+const UNIT_MODE: u32 = 0o644;
+
+/// Directory in which the environment files of services reside by default
+pub const SYSCONFIG_DIR: &str = "/etc/sysconfig";
+
+/// Permissions of generated environment files
+const ENVIRONMENT_FILE_MODE: u32 = 0o644;
+
+/// Service types accepted by systemd
+const SERVICE_TYPES: &[&str] = &["simple", "exec", "forking", "oneshot", "notify", "idle"];
+
+/// Restart policies accepted by systemd
+const RESTART_POLICIES: &[&str] = &[
+    "no",
+    "on-success",
+    "on-failure",
+    "on-abnormal",
+    "on-watchdog",
+    "on-abort",
+    "always",
+];
+
+/// Types of systemd units we can generate
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UnitType {
+    /// Service (`.service`)
+    Service,
+
+    /// Socket (`.socket`)
+    Socket,
+
+    /// Timer (`.timer`)
+    Timer,
+}
+
+impl UnitType {
+    /// Determine the type of a unit from its name
+    pub fn from_unit_name(name: &str) -> Result<Self, Error> {
+        if name.contains('/') {
+            fail!(ErrorKind::Config, "invalid systemd unit name {:?}", name);
+        }
+
+        Ok(if name.ends_with(".service") {
+            UnitType::Service
+        } else if name.ends_with(".socket") {
+            UnitType::Socket
+        } else if name.ends_with(".timer") {
+            UnitType::Timer
+        } else {
+            fail!(
+                ErrorKind::Config,
+                "unsupported systemd unit {:?} (expected a .service, .socket or .timer)",
+                name
+            )
+        })
+    }
+
+    /// Target which wants units of this type by default
+    fn default_wanted_by(self) -> &'static str {
+        match self {
+            UnitType::Service => "multi-user.target",
+            UnitType::Socket => "sockets.target",
+            UnitType::Timer => "timers.target",
+        }
+    }
+}
+
+/// Render the systemd units configured in Cargo.toml into `output_dir`,
+/// adding the scriptlets which enable, stop and restart them
+pub fn generate_units(
+    package: &PackageConfig,
+    config_dir: &Path,
+    output_dir: &Path,
+    dist: Option<Dist>,
+    scriptlets: &mut Scriptlets,
+) -> Result<Vec<GeneratedFile>, Error> {
+    let rpm_metadata = match package.rpm_metadata() {
+        Some(rpm_metadata) => rpm_metadata,
+        None => return Ok(vec![]),
+    };
+
+    let units = match rpm_metadata.systemd {
+        Some(ref units) if !units.is_empty() => units,
+        _ => return Ok(vec![]),
+    };
+
+    let mut generated = vec![];
+    let mut environment_files = BTreeMap::new();
+
+    for (name, unit) in units {
+        let key = config_key("systemd", name);
+        let rendered = render_unit(package, rpm_metadata, name, unit, config_dir, dist)
+            .map_err(|e| e.with_key(&key))?;
+
+        if UnitType::from_unit_name(name)? == UnitType::Service {
+            add_environment(&mut environment_files, name, unit, &key)
+                .map_err(|e| e.with_key(&key))?;
+        }
+
+        let src_path = output_dir.join(name);
+        fs::write(&src_path, rendered)?;
+
+        generated.push(GeneratedFile {
+            src_path,
+            install_path: Path::new(UNIT_DIR).join(name),
+            config_key: key,
+            mode: UNIT_MODE,
+            noreplace: false,
+        });
+    }
+
+    for (i, (install_path, (key, variables))) in environment_files.into_iter().enumerate() {
+        let src_path = output_dir.join(format!("environment-{}", i));
+        fs::write(&src_path, render_environment(&install_path, &variables))?;
+
+        generated.push(GeneratedFile {
+            src_path,
+            install_path,
+            config_key: key,
+            mode: ENVIRONMENT_FILE_MODE,
+            noreplace: true,
+        });
+    }
+
+    let names: Vec<&str> = units.keys().map(String::as_str).collect();
+    add_scriptlets(&names, dist, scriptlets);
+
+    Ok(generated)
+}
+
+/// Render a single unit from its template
+fn render_unit(
+    package: &PackageConfig,
+    rpm_metadata: &RpmConfig,
+    name: &str,
+    unit: &UnitConfig,
+    config_dir: &Path,
+    dist: Option<Dist>,
+) -> Result<String, Error> {
+    let unit_type = UnitType::from_unit_name(name)?;
+    let template_path = unit.template.as_ref().map(|path| config_dir.join(path));
+    let template_path = template_path.as_deref();
+
+    let description = unit
+        .description
+        .clone()
+        .unwrap_or_else(|| package.description.clone());
+
+    let wanted_by = wanted_by(name, unit_type, unit)?;
+
+    // Sockets and timers activate the service with the same name by default
+    // (or, for templates, the same instance of it)
+    let activated_unit = || {
+        unit.unit.clone().unwrap_or_else(|| {
+            if is_template(name) {
+                format!("{}@%i.service", unit_stem(name))
+            } else {
+                format!("{}.service", unit_stem(name))
+            }
+        })
+    };
+
+    match unit_type {
+        UnitType::Service => {
+            service_params(rpm_metadata, name, unit, dist, description, wanted_by)?
+                .render(template_path)
+        }
+        UnitType::Socket => {
+            let listen_stream = unit.listen_stream.clone().unwrap_or_default();
+            let listen_datagram = unit.listen_datagram.clone().unwrap_or_default();
+
+            if listen_stream.is_empty() && listen_datagram.is_empty() {
+                fail!(
+                    ErrorKind::Config,
+                    "socket has neither `listen_stream` nor `listen_datagram`"
+                );
+            }
+
+            SocketParams {
+                description,
+                listen_stream,
+                listen_datagram,
+                unit: activated_unit(),
+                wanted_by,
+                default_instance: unit.default_instance.clone(),
+            }
+            .render(template_path)
+        }
+        UnitType::Timer => {
+            if unit.on_calendar.is_none()
+                && unit.on_boot_sec.is_none()
+                && unit.on_unit_active_sec.is_none()
+            {
+                fail!(
+                    ErrorKind::Config,
+                    "timer has none of `on_calendar`, `on_boot_sec` or `on_unit_active_sec`"
+                );
+            }
+
+            TimerParams {
+                description,
+                on_calendar: unit.on_calendar.clone(),
+                on_boot_sec: unit.on_boot_sec.clone(),
+                on_unit_active_sec: unit.on_unit_active_sec.clone(),
+                persistent: unit.persistent.unwrap_or(false),
+                unit: activated_unit(),
+                wanted_by,
+                default_instance: unit.default_instance.clone(),
+            }
+            .render(template_path)
+        }
+    }
+}
+
+/// Parameters of a service's template, with defaults applied
+fn service_params(
+    rpm_metadata: &RpmConfig,
+    name: &str,
+    unit: &UnitConfig,
+    dist: Option<Dist>,
+    description: String,
+    wanted_by: Option<String>,
+) -> Result<ServiceParams, Error> {
+    let service_type = unit.service_type.as_deref().unwrap_or("simple");
+
+    if !SERVICE_TYPES.contains(&service_type) {
+        fail!(
+            ErrorKind::Config,
+            "unknown service type {:?} (expected one of: {})",
+            service_type,
+            SERVICE_TYPES.join(", ")
+        );
+    }
+
+    let restart = unit.restart.as_deref().unwrap_or("on-failure");
+
+    if !RESTART_POLICIES.contains(&restart) {
+        fail!(
+            ErrorKind::Config,
+            "unknown restart policy {:?} (expected one of: {})",
+            restart,
+            RESTART_POLICIES.join(", ")
+        );
+    }
+
+    let hardening = unit.hardening.unwrap_or(true);
+    let legacy_systemd = dist.map(Dist::is_legacy).unwrap_or(false);
+
+    if legacy_systemd {
+        for (option, value) in &[
+            ("state_directory", &unit.state_directory),
+            ("logs_directory", &unit.logs_directory),
+        ] {
+            if value.is_some() {
+                fail!(
+                    ErrorKind::Config,
+                    "`{}` needs systemd 235 or later, which {} doesn't have",
+                    option,
+                    dist.unwrap()
+                );
+            }
+        }
+
+        if unit.user.is_some() && unit.capabilities.is_some() {
+            fail!(
+                ErrorKind::Config,
+                "granting `capabilities` to a `user` needs systemd 229 or later, which {} doesn't have",
+                dist.unwrap()
+            );
+        }
+
+        // `ProtectSystem=full` only makes `/usr`, `/boot` and `/etc` read-only,
+        // so these options aren't needed (or rendered) there
+        for (option, value) in &[("read_write_paths", &unit.read_write_paths)] {
+            if hardening && value.is_some() {
+                status_warn!(
+                    "{}: `{}` is ignored on {}, which only supports ProtectSystem=full",
+                    config_key("systemd", name),
+                    option,
+                    dist.unwrap()
+                );
+            }
+        }
+    }
+
+    // Hardened services can only write below the directories systemd makes
+    // for them, so give them somewhere to keep their state
+    let state_directory = match unit.state_directory {
+        Some(ref dir) => Some(dir.clone()),
+        None if hardening && !legacy_systemd => Some(unit_stem(name).to_owned()),
+        None => None,
+    };
+
+    let capabilities = unit.capabilities.clone().unwrap_or_default();
+
+    for capability in &capabilities {
+        if !capability.starts_with("CAP_") {
+            fail!(
+                ErrorKind::Config,
+                "invalid capability {:?} (expected e.g. CAP_NET_BIND_SERVICE)",
+                capability
+            );
+        }
+    }
+
+    Ok(ServiceParams {
+        description,
+        bin_path: target_path(rpm_metadata, name, unit)?,
+        args: unit.args.clone().unwrap_or_default(),
+        service_type: service_type.to_owned(),
+        user: unit.user.clone(),
+        group: unit.group.clone(),
+        environment_file: environment_file(name, unit),
+        working_directory: unit.working_directory.clone(),
+        restart: restart.to_owned(),
+        drop_capabilities: capabilities.is_empty() && hardening && unit.user.is_some(),
+        capabilities: if capabilities.is_empty() {
+            None
+        } else {
+            Some(capabilities.join(" "))
+        },
+        state_directory,
+        runtime_directory: unit.runtime_directory.clone(),
+        logs_directory: unit.logs_directory.clone(),
+        read_write_paths: unit.read_write_paths.clone().unwrap_or_default(),
+        hardening,
+        legacy_systemd,
+        wanted_by,
+        default_instance: unit.default_instance.clone(),
+    })
+}
+
+/// Target which wants a unit when it's enabled. Template units can only be
+/// enabled through their `default_instance`, so have none without one.
+fn wanted_by(name: &str, unit_type: UnitType, unit: &UnitConfig) -> Result<Option<String>, Error> {
+    if !is_template(name) {
+        if unit.default_instance.is_some() {
+            fail!(
+                ErrorKind::Config,
+                "`default_instance` only applies to template units (e.g. foo@.service)"
+            );
+        }
+    } else if unit.default_instance.is_none() {
+        if unit.wanted_by.is_some() {
+            fail!(
+                ErrorKind::Config,
+                "a template unit needs a `default_instance` to be wanted by `wanted_by`"
+            );
+        }
+
+        return Ok(None);
+    }
+
+    Ok(Some(unit.wanted_by.clone().unwrap_or_else(|| {
+        unit_type.default_wanted_by().to_owned()
+    })))
+}
+
+/// Path of the environment file a service reads
+fn environment_file(name: &str, unit: &UnitConfig) -> PathBuf {
+    unit.environment_file
+        .clone()
+        .unwrap_or_else(|| Path::new(SYSCONFIG_DIR).join(unit_stem(name)))
+}
+
+/// Add a service's variables to the environment file it reads. Services may
+/// share an environment file (e.g. `foo.service` and `foo@.service`), so long
+/// as they don't set the same variable to different values.
+fn add_environment(
+    environment_files: &mut BTreeMap<PathBuf, (String, BTreeMap<String, String>)>,
+    name: &str,
+    unit: &UnitConfig,
+    key: &str,
+) -> Result<(), Error> {
+    let (_, variables) = environment_files
+        .entry(environment_file(name, unit))
+        .or_insert_with(|| (key.to_owned(), BTreeMap::new()));
+
+    for (variable, value) in unit.environment.iter().flatten() {
+        check_variable(variable, value)?;
+
+        match variables.get(variable) {
+            Some(other) if other != value => fail!(
+                ErrorKind::Config,
+                "environment variable {} is set to a different value by another service",
+                variable
+            ),
+            _ => {
+                variables.insert(variable.clone(), value.clone());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that an environment variable has a valid name, and a value which
+/// fits on one line
+pub fn check_variable(variable: &str, value: &str) -> Result<(), Error> {
+    let valid = variable
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+
+    if variable.is_empty() || !valid {
+        fail!(
+            ErrorKind::Config,
+            "invalid environment variable name {:?}",
+            variable
+        );
+    }
+
+    if value.contains('\n') {
+        fail!(
+            ErrorKind::Config,
+            "value of environment variable {} contains a newline",
+            variable
+        );
+    }
+
+    Ok(())
+}
+
+/// Render an environment file setting the given variables
+fn render_environment(path: &Path, variables: &BTreeMap<String, String>) -> String {
+    let mut contents = format!(
+        "# Environment of the services reading {}\n# (KEY=\"value\" per line)\n",
+        path.display()
+    );
+
+    for (variable, value) in variables {
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+        contents.push_str(&format!("{}=\"{}\"\n", variable, value));
+    }
+
+    contents
+}
+
+/// Find the install path of the target a service runs: the configured
+/// `target`, else the one named after the unit, else the only target
+fn target_path(rpm_metadata: &RpmConfig, name: &str, unit: &UnitConfig) -> Result<PathBuf, Error> {
+    let targets = &rpm_metadata.targets;
+
+    let target = match unit.target {
+        Some(ref target) => targets.get(target).ok_or_else(|| {
+            format_err!(
+                ErrorKind::Config,
+                "unknown target {:?} (not in [package.metadata.rpm.targets])",
+                target
+            )
+        })?,
+        None => match targets.get(unit_stem(name)) {
+            Some(target) => target,
+            None if targets.len() == 1 => targets.values().next().unwrap(),
+            None => fail!(
+                ErrorKind::Config,
+                "can't tell which target the service runs (set `target`)"
+            ),
+        },
+    };
+
+    Ok(target.path.clone())
+}
+
+/// Is the unit a template for other units, e.g. `foo@.service`?
+fn is_template(name: &str) -> bool {
+    name.contains("@.")
+}
+
+/// Name of a unit without its type suffix (or template `@`), e.g. `foo` for
+/// `foo.socket` or `foo@.service`
+fn unit_stem(name: &str) -> &str {
+    let stem = match name.rfind('.') {
+        Some(pos) => &name[..pos],
+        None => name,
+    };
+
+    stem.trim_end_matches('@')
+}
+
+/// Add the scriptlets (and their dependencies) which enable units according
+/// to presets on install, stop and disable them on removal, and restart them
+/// on upgrade
+fn add_scriptlets(names: &[&str], dist: Option<Dist>, scriptlets: &mut Scriptlets) {
+    let units = names.join(" ");
+
+    if dist == Some(Dist::Suse) {
+        scriptlets.add_preamble("BuildRequires: systemd-rpm-macros");
+        scriptlets.add_preamble("%{?systemd_requires}");
+        scriptlets.pre.push(format!("%service_add_pre {}", units));
+        scriptlets.post.push(format!("%service_add_post {}", units));
+        scriptlets
+            .preun
+            .push(format!("%service_del_preun {}", units));
+        scriptlets
+            .postun
+            .push(format!("%service_del_postun {}", units));
+        return;
+    }
+
+    // Template units can't be restarted themselves, only their instances
+    let restart_units = names
+        .iter()
+        .map(|name| name.replacen("@.", "@*.", 1))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let macros_package = dist.map(Dist::systemd_macros_package).unwrap_or("systemd");
+    scriptlets.add_preamble(format!("BuildRequires: {}", macros_package));
+
+    for scriptlet in &["post", "preun", "postun"] {
+        scriptlets.add_preamble(format!("Requires({}): systemd", scriptlet));
+    }
+
+    scriptlets.post.push(format!("%systemd_post {}", units));
+    scriptlets.preun.push(format!("%systemd_preun {}", units));
+    scriptlets
+        .postun
+        .push(format!("%systemd_postun_with_restart {}", restart_units));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package() -> PackageConfig {
+        serde_json::from_str(
+            r#"{
+                "name": "foo",
+                "version": "1.0.0",
+                "description": "Foo daemon",
+                "license": "MIT",
+                "metadata": {
+                    "rpm": {
+                        "targets": {
+                            "foo": { "path": "/usr/bin/foo" },
+                            "bar": { "path": "/usr/sbin/bar" }
+                        }
+                    }
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn render(name: &str, unit: &UnitConfig) -> Result<String, Error> {
+        let package = package();
+        let rpm_metadata = package.rpm_metadata().unwrap();
+        render_unit(&package, rpm_metadata, name, unit, Path::new("."), None)
+    }
+
+    #[test]
+    fn unit_stems() {
+        assert_eq!(unit_stem("foo.service"), "foo");
+        assert_eq!(unit_stem("foo.bar.socket"), "foo.bar");
+        assert_eq!(unit_stem("foo@.service"), "foo");
+        assert_eq!(unit_stem("foo@gc.service"), "foo@gc");
+        assert_eq!(unit_stem("foo"), "foo");
+    }
+
+    #[test]
+    fn target_paths() {
+        let package = package();
+        let rpm_metadata = package.rpm_metadata().unwrap();
+        let path = |name: &str, target: Option<&str>| {
+            let unit = UnitConfig {
+                target: target.map(str::to_owned),
+                ..Default::default()
+            };
+            target_path(rpm_metadata, name, &unit)
+        };
+
+        assert_eq!(
+            path("foo.service", None).unwrap(),
+            Path::new("/usr/bin/foo")
+        );
+        assert_eq!(
+            path("bar@.service", None).unwrap(),
+            Path::new("/usr/sbin/bar")
+        );
+        assert_eq!(
+            path("baz.service", Some("bar")).unwrap(),
+            Path::new("/usr/sbin/bar")
+        );
+        assert!(path("baz.service", None).is_err());
+        assert!(path("foo.service", Some("baz")).is_err());
+    }
+
+    #[test]
+    fn install_sections() {
+        let service = render("foo.service", &UnitConfig::default()).unwrap();
+        assert!(service.ends_with("\n\n[Install]\nWantedBy=multi-user.target\n"));
+
+        // Templates are only enabled through their default instance
+        let template = render("foo@.service", &UnitConfig::default()).unwrap();
+        assert!(!template.contains("[Install]"));
+        assert!(!template.ends_with("\n\n"));
+
+        let unit = UnitConfig {
+            default_instance: Some("main".to_owned()),
+            ..Default::default()
+        };
+        let template = render("foo@.service", &unit).unwrap();
+        assert!(template.ends_with("[Install]\nWantedBy=multi-user.target\nDefaultInstance=main\n"));
+        assert!(render("foo.service", &unit).is_err());
+
+        let unit = UnitConfig {
+            wanted_by: Some("default.target".to_owned()),
+            ..Default::default()
+        };
+        assert!(render("foo@.service", &unit).is_err());
+    }
+
+    #[test]
+    fn activated_units() {
+        let unit = UnitConfig {
+            on_calendar: Some("daily".to_owned()),
+            ..Default::default()
+        };
+        assert!(render("foo-gc.timer", &unit)
+            .unwrap()
+            .contains("\nUnit=foo-gc.service\n"));
+        assert!(render("foo-gc@.timer", &unit)
+            .unwrap()
+            .contains("\nUnit=foo-gc@%i.service\n"));
+    }
+
+    #[test]
+    fn scriptlets() {
+        let names = ["foo.service", "foo@.service", "foo.socket"];
+        let mut scriptlets = Scriptlets::default();
+        add_scriptlets(&names, Some(Dist::El9), &mut scriptlets);
+
+        assert_eq!(
+            scriptlets.preamble,
+            [
+                "BuildRequires: systemd-rpm-macros",
+                "Requires(post): systemd",
+                "Requires(preun): systemd",
+                "Requires(postun): systemd",
+            ]
+        );
+        assert!(scriptlets.pre.is_empty());
+        assert_eq!(
+            scriptlets.post,
+            ["%systemd_post foo.service foo@.service foo.socket"]
+        );
+        assert_eq!(
+            scriptlets.preun,
+            ["%systemd_preun foo.service foo@.service foo.socket"]
+        );
+        assert_eq!(
+            scriptlets.postun,
+            ["%systemd_postun_with_restart foo.service foo@*.service foo.socket"]
+        );
+
+        let mut scriptlets = Scriptlets::default();
+        add_scriptlets(&names, Some(Dist::Suse), &mut scriptlets);
+        assert_eq!(
+            scriptlets.pre,
+            ["%service_add_pre foo.service foo@.service foo.socket"]
+        );
+        assert_eq!(
+            scriptlets.postun,
+            ["%service_del_postun foo.service foo@.service foo.socket"]
+        );
+    }
+}
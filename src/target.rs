@@ -4,8 +4,11 @@ use crate::{
     error::{Error, ErrorKind},
     prelude::*,
 };
+use cargo_metadata::Message;
 use std::{
+    collections::BTreeMap,
     env, fs,
+    io::BufRead,
     path::{Path, PathBuf},
 };
 
@@ -22,6 +25,37 @@ pub fn find_dir() -> Result<PathBuf, Error> {
         .map_err(|err| format_err!(ErrorKind::Target, "failed to fetch metadata: {}", err).into())
 }
 
+/// Find the `OUT_DIR` of each crate's build script (by crate name) from the
+/// JSON messages emitted by `cargo build --message-format=json`
+pub fn find_out_dirs<R: BufRead>(messages: R) -> Result<BTreeMap<String, PathBuf>, Error> {
+    let mut out_dirs_by_id = vec![];
+
+    for message in Message::parse_stream(messages) {
+        if let Message::BuildScriptExecuted(script) = message? {
+            out_dirs_by_id.push((script.package_id, script.out_dir));
+        }
+    }
+
+    if out_dirs_by_id.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .exec()
+        .map_err(|err| format_err!(ErrorKind::Target, "failed to fetch metadata: {}", err))?;
+
+    Ok(out_dirs_by_id
+        .into_iter()
+        .filter_map(|(id, out_dir)| {
+            metadata
+                .packages
+                .iter()
+                .find(|package| package.id == id)
+                .map(|package| (package.name.clone(), out_dir))
+        })
+        .collect())
+}
+
 /// Target types we can autodetect
 pub enum TargetType {
     /// Library crate i.e. `lib.rs` (we don't support these yet)
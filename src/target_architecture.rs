@@ -8,12 +8,61 @@ use crate::{
     error::{Error, ErrorKind},
     prelude::*,
 };
+use std::{env, process::Command};
+
+/// Pseudo-target used for architecture-independent (i.e. data-only) packages
+pub const NOARCH: &str = "noarch";
 
 /// Target architectures for which we have a defined rpm target architecture.
-/// Only the architectures that vary in name between the rust and rpm target
-/// need to be listed here explictly (others that follow the same naming across
-/// both targets can be used as is).
+/// Every Linux target in `rustc --print target-list` is either listed here or
+/// explicitly rejected by `TargetArch::parse`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum TargetArch {
+    /// aarch64
+    aarch64,
+
+    /// arm (ARMv6)
+    arm,
+
+    /// arm (ARMv6, hard-float)
+    arm_hf,
+
+    /// armv4t
+    armv4t,
+
+    /// armv5te
+    armv5te,
+
+    /// armv7
+    armv7,
+
+    /// armv7 (hard-float)
+    armv7_hf,
+
+    /// i586
+    i586,
+
+    /// i686
+    i686,
+
+    /// loongarch64
+    loongarch64,
+
+    /// m68k
+    m68k,
+
+    /// mips
+    mips,
+
+    /// mipsel
+    mipsel,
+
+    /// mips64
+    mips64,
+
+    /// mips64el
+    mips64el,
+
     /// mipsisa32r6
     mipsisa32r6,
 
@@ -38,36 +87,52 @@ pub enum TargetArch {
     /// riscv64gc
     riscv64gc,
 
-    /// x86
-    x86,
+    /// s390x
+    s390x,
 
-    /// arm (hard-float)
-    arm_hf,
+    /// sparc
+    sparc,
 
-    /// arm
-    arm,
+    /// sparc64
+    sparc64,
 
-    /// for all other architectures
-    Other(String),
+    /// thumbv7neon (armv7 with NEON, hard-float)
+    thumbv7neon,
+
+    /// x86_64
+    x86_64,
+
+    /// Architecture-independent package (no Rust target)
+    noarch,
 }
 
 impl TargetArch {
     /// Parse a specific rust target triple into the `TargetArch` enum
     pub fn parse(rust_target_triple: &str) -> Result<Self, Error> {
-        let mut parts = rust_target_triple.split('-');
-        let arch = parts.next().ok_or_else(|| {
-            format_err!(
-                ErrorKind::Parse,
-                "no arch in the rust target {}!",
-                rust_target_triple
-            )
-        })?;
+        if rust_target_triple == NOARCH {
+            return Ok(TargetArch::noarch);
+        }
 
-        let abi = parts.last().unwrap_or("");
+        let (arch, abi) = split_linux_target(rust_target_triple)?;
 
         // https://doc.rust-lang.org/std/env/consts/constant.ARCH.html
         // rustc --print target-list
         Ok(match (arch, abi) {
+            ("aarch64", _) => TargetArch::aarch64,
+            ("arm", "eabihf") => TargetArch::arm_hf,
+            ("arm", _) => TargetArch::arm,
+            ("armv4t", _) => TargetArch::armv4t,
+            ("armv5te", _) => TargetArch::armv5te,
+            ("armv7", "eabihf") => TargetArch::armv7_hf,
+            ("armv7", _) => TargetArch::armv7,
+            ("i586", _) => TargetArch::i586,
+            ("i686", _) => TargetArch::i686,
+            ("loongarch64", _) => TargetArch::loongarch64,
+            ("m68k", _) => TargetArch::m68k,
+            ("mips", _) => TargetArch::mips,
+            ("mipsel", _) => TargetArch::mipsel,
+            ("mips64", _) => TargetArch::mips64,
+            ("mips64el", _) => TargetArch::mips64el,
             ("mipsisa32r6", _) => TargetArch::mipsisa32r6,
             ("mipsisa32r6el", _) => TargetArch::mipsisa32r6el,
             ("mipsisa64r6", _) => TargetArch::mipsisa64r6,
@@ -75,24 +140,116 @@ impl TargetArch {
             ("powerpc", _) => TargetArch::powerpc,
             ("powerpc64", _) => TargetArch::powerpc64,
             ("powerpc64le", _) => TargetArch::powerpc64le,
-            ("riscv64gc", _) => TargetArch::riscv64gc,
-            ("x86", _) => TargetArch::x86,
-            (arm, gnueabi) if arm.starts_with("arm") && gnueabi.ends_with("hf") => {
-                TargetArch::arm_hf
-            }
-            (arm, _) if arm.starts_with("arm") => TargetArch::arm,
-            (other_arch, _) => TargetArch::Other(other_arch.to_owned()),
+            ("riscv64gc", _) | ("riscv64a23", _) => TargetArch::riscv64gc,
+            ("s390x", _) => TargetArch::s390x,
+            ("sparc", _) => TargetArch::sparc,
+            ("sparc64", _) => TargetArch::sparc64,
+            ("thumbv7neon", "eabihf") => TargetArch::thumbv7neon,
+            ("x86_64", _) => TargetArch::x86_64,
+            // aarch64_be, armeb, csky, hexagon, riscv32gc and wasm32 have no
+            // corresponding rpm architecture
+            _ => fail!(
+                ErrorKind::Parse,
+                "no known rpm target architecture for rust target {} \
+                 (set target_architecture in [package.metadata.rpm] to override)",
+                rust_target_triple
+            ),
+        })
+    }
+
+    /// Check that a rust target triple is for a Linux system which can
+    /// install RPMs, whether or not it has a known rpm architecture (which
+    /// `target_architecture` in Cargo.toml can provide)
+    pub fn check_rust_target(rust_target_triple: &str) -> Result<(), Error> {
+        if rust_target_triple != NOARCH {
+            split_linux_target(rust_target_triple)?;
+        }
+
+        Ok(())
+    }
+
+    /// Determine the `TargetArch` of the host from `rustc -vV`
+    pub fn host() -> Result<Self, Error> {
+        let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+
+        let output = Command::new(&rustc)
+            .arg("-vV")
+            .output()
+            .map_err(|e| format_err!(ErrorKind::Target, "error running {:?}: {}", rustc, e))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let host = stdout
+            .lines()
+            .find(|line| line.starts_with("host: "))
+            .map(|line| &line["host: ".len()..])
+            .ok_or_else(|| format_err!(ErrorKind::Target, "couldn't determine host rust target"))?;
+
+        Self::parse(host.trim())
+    }
+
+    /// Look up the `TargetArch` for a given rpm target architecture name
+    pub fn from_rpm_arch(rpm_arch: &str) -> Result<Self, Error> {
+        Ok(match rpm_arch {
+            "aarch64" => TargetArch::aarch64,
+            "armv6l" => TargetArch::arm,
+            "armv6hl" => TargetArch::arm_hf,
+            "armv4tl" => TargetArch::armv4t,
+            "armv5tel" => TargetArch::armv5te,
+            "armv7l" => TargetArch::armv7,
+            "armv7hl" => TargetArch::armv7_hf,
+            "armv7hnl" => TargetArch::thumbv7neon,
+            "i586" => TargetArch::i586,
+            "i686" => TargetArch::i686,
+            "loongarch64" => TargetArch::loongarch64,
+            "m68k" => TargetArch::m68k,
+            "mips" => TargetArch::mips,
+            "mipsel" => TargetArch::mipsel,
+            "mips64" => TargetArch::mips64,
+            "mips64el" => TargetArch::mips64el,
+            "mipsr6" => TargetArch::mipsisa32r6,
+            "mipsr6el" => TargetArch::mipsisa32r6el,
+            "mips64r6" => TargetArch::mipsisa64r6,
+            "mips64r6el" => TargetArch::mipsisa64r6el,
+            "ppc" => TargetArch::powerpc,
+            "ppc64" => TargetArch::powerpc64,
+            "ppc64le" => TargetArch::powerpc64le,
+            "riscv64" => TargetArch::riscv64gc,
+            "s390x" => TargetArch::s390x,
+            "sparc" => TargetArch::sparc,
+            "sparc64" => TargetArch::sparc64,
+            "x86_64" => TargetArch::x86_64,
+            NOARCH => TargetArch::noarch,
+            _ => fail!(
+                ErrorKind::Parse,
+                "no known rust target for rpm target architecture {}",
+                rpm_arch
+            ),
         })
     }
 
     /// Return a rpm target architecture name
-    pub fn as_rpm_target_architecture(&self) -> &str {
+    pub fn as_rpm_target_architecture(self) -> &'static str {
         // based on the similar function from cargo-deb:
         // https://github.com/mmstick/cargo-deb/blob/v1.23.1/src/manifest.rs#L909-L937
         // with adjustments for valid values that `rpmbuild --target` takes
         match self {
             // https://fedoraproject.org/wiki/Architectures
             // https://github.com/rpm-software-management/rpm/blob/rpm-4.14.3-release/rpmrc.in#L156
+            TargetArch::aarch64 => "aarch64",
+            TargetArch::arm => "armv6l",
+            TargetArch::arm_hf => "armv6hl",
+            TargetArch::armv4t => "armv4tl",
+            TargetArch::armv5te => "armv5tel",
+            TargetArch::armv7 => "armv7l",
+            TargetArch::armv7_hf => "armv7hl",
+            TargetArch::i586 => "i586",
+            TargetArch::i686 => "i686",
+            TargetArch::loongarch64 => "loongarch64",
+            TargetArch::m68k => "m68k",
+            TargetArch::mips => "mips",
+            TargetArch::mipsel => "mipsel",
+            TargetArch::mips64 => "mips64",
+            TargetArch::mips64el => "mips64el",
             TargetArch::mipsisa32r6 => "mipsr6",
             TargetArch::mipsisa32r6el => "mipsr6el",
             TargetArch::mipsisa64r6 => "mips64r6",
@@ -101,10 +258,258 @@ impl TargetArch {
             TargetArch::powerpc64 => "ppc64",
             TargetArch::powerpc64le => "ppc64le",
             TargetArch::riscv64gc => "riscv64",
-            TargetArch::x86 => "i386",
-            TargetArch::arm_hf => "armv7hl",
-            TargetArch::arm => "armv7l",
-            TargetArch::Other(arch) => arch,
+            TargetArch::s390x => "s390x",
+            TargetArch::sparc => "sparc",
+            TargetArch::sparc64 => "sparc64",
+            TargetArch::thumbv7neon => "armv7hnl",
+            TargetArch::x86_64 => "x86_64",
+            TargetArch::noarch => NOARCH,
         }
     }
+
+    /// Return the canonical rust target triple for this architecture
+    /// (or `None` for `noarch`)
+    pub fn as_rust_target_triple(self) -> Option<&'static str> {
+        Some(match self {
+            TargetArch::aarch64 => "aarch64-unknown-linux-gnu",
+            TargetArch::arm => "arm-unknown-linux-gnueabi",
+            TargetArch::arm_hf => "arm-unknown-linux-gnueabihf",
+            TargetArch::armv4t => "armv4t-unknown-linux-gnueabi",
+            TargetArch::armv5te => "armv5te-unknown-linux-gnueabi",
+            TargetArch::armv7 => "armv7-unknown-linux-gnueabi",
+            TargetArch::armv7_hf => "armv7-unknown-linux-gnueabihf",
+            TargetArch::i586 => "i586-unknown-linux-gnu",
+            TargetArch::i686 => "i686-unknown-linux-gnu",
+            TargetArch::loongarch64 => "loongarch64-unknown-linux-gnu",
+            TargetArch::m68k => "m68k-unknown-linux-gnu",
+            TargetArch::mips => "mips-unknown-linux-gnu",
+            TargetArch::mipsel => "mipsel-unknown-linux-gnu",
+            TargetArch::mips64 => "mips64-unknown-linux-gnuabi64",
+            TargetArch::mips64el => "mips64el-unknown-linux-gnuabi64",
+            TargetArch::mipsisa32r6 => "mipsisa32r6-unknown-linux-gnu",
+            TargetArch::mipsisa32r6el => "mipsisa32r6el-unknown-linux-gnu",
+            TargetArch::mipsisa64r6 => "mipsisa64r6-unknown-linux-gnuabi64",
+            TargetArch::mipsisa64r6el => "mipsisa64r6el-unknown-linux-gnuabi64",
+            TargetArch::powerpc => "powerpc-unknown-linux-gnu",
+            TargetArch::powerpc64 => "powerpc64-unknown-linux-gnu",
+            TargetArch::powerpc64le => "powerpc64le-unknown-linux-gnu",
+            TargetArch::riscv64gc => "riscv64gc-unknown-linux-gnu",
+            TargetArch::s390x => "s390x-unknown-linux-gnu",
+            TargetArch::sparc => "sparc-unknown-linux-gnu",
+            TargetArch::sparc64 => "sparc64-unknown-linux-gnu",
+            TargetArch::thumbv7neon => "thumbv7neon-unknown-linux-gnueabihf",
+            TargetArch::x86_64 => "x86_64-unknown-linux-gnu",
+            TargetArch::noarch => return None,
+        })
+    }
+}
+
+/// Split a rust target triple for a Linux system which can install RPMs
+/// into its architecture and ABI (the part of its environment after `gnu` or
+/// `musl`, e.g. `eabihf`)
+fn split_linux_target(rust_target_triple: &str) -> Result<(&str, &str), Error> {
+    let parts: Vec<&str> = rust_target_triple.split('-').collect();
+
+    if !parts.contains(&"linux") {
+        fail!(
+            ErrorKind::Parse,
+            "rust target {} is not a Linux target (RPMs can only be built for Linux)",
+            rust_target_triple
+        );
+    }
+
+    let env = parts.last().cloned().unwrap_or("");
+
+    if env.contains("android") || env == "ohos" {
+        fail!(
+            ErrorKind::Parse,
+            "rust target {} is for Android or OpenHarmony, which can't install RPMs",
+            rust_target_triple
+        );
+    }
+
+    let (arch, vendor) = match parts.as_slice() {
+        [arch, vendor, "linux", _] => (*arch, *vendor),
+        _ => fail!(
+            ErrorKind::Parse,
+            "rust target {} isn't of the form <arch>-<vendor>-linux-<env>",
+            rust_target_triple
+        ),
+    };
+
+    if vendor != "unknown" && vendor != "pc" {
+        fail!(
+            ErrorKind::Parse,
+            "rust target {} is for {}, not a general-purpose Linux distribution",
+            rust_target_triple,
+            vendor
+        );
+    }
+
+    // Only glibc and musl binaries run on RPM-based distributions
+    let abi = match ["gnu", "musl"].iter().find(|libc| env.starts_with(*libc)) {
+        Some(libc) => &env[libc.len()..],
+        None => fail!(
+            ErrorKind::Parse,
+            "rust target {} uses the {:?} environment (expected gnu or musl)",
+            rust_target_triple,
+            env
+        ),
+    };
+
+    if abi.ends_with("spe") {
+        fail!(
+            ErrorKind::Parse,
+            "rust target {} uses the PowerPC SPE ABI, which is incompatible with rpm's ppc",
+            rust_target_triple
+        );
+    }
+
+    // x32, ILP32 and sanitizer builds have no corresponding rpm
+    // architecture, or aren't meant to be distributed
+    if !["", "eabi", "eabihf", "abi64"].contains(&abi) {
+        fail!(
+            ErrorKind::Parse,
+            "rust target {} uses the unsupported {:?} ABI",
+            rust_target_triple,
+            env
+        );
+    }
+
+    Ok((arch, abi))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TargetArch, NOARCH};
+
+    /// Every architecture other than `noarch`
+    const ALL: &[TargetArch] = &[
+        TargetArch::aarch64,
+        TargetArch::arm,
+        TargetArch::arm_hf,
+        TargetArch::armv4t,
+        TargetArch::armv5te,
+        TargetArch::armv7,
+        TargetArch::armv7_hf,
+        TargetArch::i586,
+        TargetArch::i686,
+        TargetArch::loongarch64,
+        TargetArch::m68k,
+        TargetArch::mips,
+        TargetArch::mipsel,
+        TargetArch::mips64,
+        TargetArch::mips64el,
+        TargetArch::mipsisa32r6,
+        TargetArch::mipsisa32r6el,
+        TargetArch::mipsisa64r6,
+        TargetArch::mipsisa64r6el,
+        TargetArch::powerpc,
+        TargetArch::powerpc64,
+        TargetArch::powerpc64le,
+        TargetArch::riscv64gc,
+        TargetArch::s390x,
+        TargetArch::sparc,
+        TargetArch::sparc64,
+        TargetArch::thumbv7neon,
+        TargetArch::x86_64,
+    ];
+
+    fn rpm_arch(triple: &str) -> &'static str {
+        TargetArch::parse(triple)
+            .unwrap_or_else(|e| panic!("{}: {}", triple, e))
+            .as_rpm_target_architecture()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(rpm_arch("x86_64-unknown-linux-gnu"), "x86_64");
+        assert_eq!(rpm_arch("x86_64-unknown-linux-musl"), "x86_64");
+        assert_eq!(rpm_arch("aarch64-unknown-linux-musl"), "aarch64");
+        assert_eq!(rpm_arch("arm-unknown-linux-gnueabi"), "armv6l");
+        assert_eq!(rpm_arch("arm-unknown-linux-musleabihf"), "armv6hl");
+        assert_eq!(rpm_arch("armv7-unknown-linux-gnueabihf"), "armv7hl");
+        assert_eq!(rpm_arch("armv7-unknown-linux-musleabi"), "armv7l");
+        assert_eq!(rpm_arch("mips64el-unknown-linux-muslabi64"), "mips64el");
+        assert_eq!(rpm_arch("powerpc-unknown-linux-musl"), "ppc");
+        assert_eq!(rpm_arch("riscv64gc-unknown-linux-gnu"), "riscv64");
+        assert_eq!(rpm_arch(NOARCH), NOARCH);
+    }
+
+    #[test]
+    fn parse_rejects_non_rpm_targets() {
+        for triple in &[
+            "x86_64-pc-windows-msvc",
+            "x86_64-apple-darwin",
+            "wasm32-unknown-unknown",
+            "aarch64-linux-android",
+            "armv7-linux-androideabi",
+            "x86_64-linux-android",
+            "aarch64-unknown-linux-ohos",
+            "x86_64-unknown-linux-ohos",
+            "x86_64-unikraft-linux-musl",
+            "mips64-openwrt-linux-musl",
+            "x86_64-unknown-linux-none",
+            "x86_64-unknown-linux-gnuasan",
+            "x86_64-unknown-linux-gnux32",
+            "aarch64-unknown-linux-gnu_ilp32",
+            "armv7-unknown-linux-uclibceabihf",
+            "powerpc-unknown-linux-gnuspe",
+            "powerpc-unknown-linux-muslspe",
+            "aarch64_be-unknown-linux-gnu",
+            "riscv32gc-unknown-linux-gnu",
+            "x86_64-unknown-linux",
+        ] {
+            assert!(
+                TargetArch::parse(triple).is_err(),
+                "{} was accepted",
+                triple
+            );
+        }
+    }
+
+    #[test]
+    fn check_rust_target() {
+        assert!(TargetArch::check_rust_target("aarch64_be-unknown-linux-gnu").is_ok());
+        assert!(TargetArch::check_rust_target(NOARCH).is_ok());
+        assert!(TargetArch::check_rust_target("x86_64-unknown-linx-gnu").is_err());
+        assert!(TargetArch::check_rust_target("aarch64-linux-android").is_err());
+    }
+
+    #[test]
+    fn from_rpm_arch() {
+        assert_eq!(
+            TargetArch::from_rpm_arch("ppc64le").unwrap(),
+            TargetArch::powerpc64le
+        );
+        assert_eq!(
+            TargetArch::from_rpm_arch("armv7hnl").unwrap(),
+            TargetArch::thumbv7neon
+        );
+        assert_eq!(
+            TargetArch::from_rpm_arch(NOARCH).unwrap(),
+            TargetArch::noarch
+        );
+        assert!(TargetArch::from_rpm_arch("amd64").is_err());
+        assert!(TargetArch::from_rpm_arch("").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        for &arch in ALL {
+            let triple = arch.as_rust_target_triple().unwrap();
+            assert_eq!(TargetArch::parse(triple).unwrap(), arch, "{}", triple);
+
+            let rpm_arch = arch.as_rpm_target_architecture();
+            assert_eq!(
+                TargetArch::from_rpm_arch(rpm_arch).unwrap(),
+                arch,
+                "{}",
+                rpm_arch
+            );
+        }
+
+        assert_eq!(TargetArch::noarch.as_rust_target_triple(), None);
+        assert_eq!(TargetArch::parse(NOARCH).unwrap(), TargetArch::noarch);
+    }
 }
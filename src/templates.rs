@@ -2,8 +2,9 @@
 
 use crate::{
     config::{CargoLicense, PackageConfig},
+    dist::Dist,
     error::{Error, ErrorKind},
-    license,
+    license::{self, LicenseStyle},
     prelude::*,
 };
 use handlebars::Handlebars;
@@ -17,9 +18,18 @@ use std::{
 /// Default RPM spec template (in toplevel `template/spec.hbs`)
 pub const DEFAULT_SPEC_TEMPLATE: &str = include_str!("../templates/spec.hbs");
 
+/// RPM spec template for openSUSE and SLES (in toplevel `template/spec-suse.hbs`)
+pub const SUSE_SPEC_TEMPLATE: &str = include_str!("../templates/spec-suse.hbs");
+
 /// Default systemd service unit template (in toplevel `template/service.hbs`)
 pub const DEFAULT_SERVICE_TEMPLATE: &str = include_str!("../templates/service.hbs");
 
+/// Default systemd socket unit template (in toplevel `template/socket.hbs`)
+pub const DEFAULT_SOCKET_TEMPLATE: &str = include_str!("../templates/socket.hbs");
+
+/// Default systemd timer unit template (in toplevel `template/timer.hbs`)
+pub const DEFAULT_TIMER_TEMPLATE: &str = include_str!("../templates/timer.hbs");
+
 /// Parameters passed to the RPM spec template
 #[derive(Serialize)]
 pub struct SpecParams {
@@ -40,6 +50,14 @@ pub struct SpecParams {
 
     /// Are we placing targets in sbin instead of bin?
     pub use_sbin: bool,
+
+    /// Include the `Group` and `BuildRoot` tags and `%clean` section needed
+    /// by older versions of rpm?
+    pub legacy: bool,
+
+    /// Distribution the spec is for (which selects the default template)
+    #[serde(skip)]
+    pub dist: Option<Dist>,
 }
 
 impl SpecParams {
@@ -49,8 +67,13 @@ impl SpecParams {
         package: &PackageConfig,
         service: Option<String>,
         use_sbin: bool,
+        dist: Option<Dist>,
     ) -> Self {
-        let rpm_license = license::convert(&package.license).unwrap_or_else(|e| {
+        let license_style = dist
+            .map(Dist::license_style)
+            .unwrap_or(LicenseStyle::Fedora);
+
+        let rpm_license = license::convert(&package.license, license_style).unwrap_or_else(|e| {
             let default_lic = match package.license {
                 CargoLicense::License(ref lic) => lic.to_owned(),
                 CargoLicense::LicenseFile(ref name) => name.to_owned(),
@@ -66,22 +89,29 @@ impl SpecParams {
             url: package.homepage.to_owned(),
             service,
             use_sbin,
+            legacy: dist.map(Dist::is_legacy).unwrap_or(true),
+            dist,
         }
     }
 
     /// Render an RPM spec template at the given path (or default)
     pub fn render(&self, template_path: Option<&Path>) -> Result<String, Error> {
+        let (default_name, default_template) = match self.dist {
+            Some(Dist::Suse) => ("(default:spec-suse.hbs)", SUSE_SPEC_TEMPLATE),
+            _ => ("(default:spec.hbs)", DEFAULT_SPEC_TEMPLATE),
+        };
+
         let name = match template_path {
             Some(p) => p.display().to_string(),
-            None => "(default:spec.hbs)".to_owned(),
+            None => default_name.to_owned(),
         };
 
-        let template = load_template(template_path, DEFAULT_SPEC_TEMPLATE)?;
+        let template = load_template(template_path, default_template)?;
         render_template(&name, &template, self)
     }
 }
 
-/// Paramters passed to the systemd service unit template
+/// Parameters passed to the systemd service unit template
 #[derive(Serialize)]
 pub struct ServiceParams {
     /// Description of the service
@@ -89,6 +119,59 @@ pub struct ServiceParams {
 
     /// Path to the binary for systemd to spawn (absolute)
     pub bin_path: PathBuf,
+
+    /// Arguments to pass to the binary
+    pub args: Vec<String>,
+
+    /// Type of the service, e.g. `simple` or `notify`
+    pub service_type: String,
+
+    /// User the service runs as
+    pub user: Option<String>,
+
+    /// Group the service runs as
+    pub group: Option<String>,
+
+    /// Environment file read by the service
+    pub environment_file: PathBuf,
+
+    /// Working directory of the service
+    pub working_directory: Option<PathBuf>,
+
+    /// Restart policy of the service
+    pub restart: String,
+
+    /// Space-separated capabilities the service keeps
+    pub capabilities: Option<String>,
+
+    /// Should the service's capabilities all be dropped?
+    pub drop_capabilities: bool,
+
+    /// Directory below `/var/lib` for the service's state
+    pub state_directory: Option<String>,
+
+    /// Directory below `/run` for the service
+    pub runtime_directory: Option<String>,
+
+    /// Directory below `/var/log` for the service's logs
+    pub logs_directory: Option<String>,
+
+    /// Paths the (hardened) service may write to
+    pub read_write_paths: Vec<String>,
+
+    /// Should the default sandboxing options be applied?
+    pub hardening: bool,
+
+    /// Is the target's systemd too old for `ProtectSystem=strict` and the
+    /// directory options (systemd 219 on EL7 and Amazon Linux 2)?
+    pub legacy_systemd: bool,
+
+    /// Target which wants the service when it's enabled (none for template
+    /// units without a default instance, which can't be enabled)
+    pub wanted_by: Option<String>,
+
+    /// Instance enabled when a template unit is
+    pub default_instance: Option<String>,
 }
 
 impl ServiceParams {
@@ -104,13 +187,81 @@ impl ServiceParams {
     }
 }
 
-impl<'a> From<&'a PackageConfig> for ServiceParams {
-    fn from(package: &'a PackageConfig) -> Self {
-        Self {
-            description: package.description.to_owned(),
-            /// TODO: better handling of target binaries and their paths
-            bin_path: PathBuf::from("/usr/sbin").join(&package.rpm_name()),
-        }
+/// Parameters passed to the systemd socket unit template
+#[derive(Serialize)]
+pub struct SocketParams {
+    /// Description of the socket
+    pub description: String,
+
+    /// Stream sockets to listen on
+    pub listen_stream: Vec<String>,
+
+    /// Datagram sockets to listen on
+    pub listen_datagram: Vec<String>,
+
+    /// Service activated by the socket
+    pub unit: String,
+
+    /// Target which wants the socket when it's enabled (none for template
+    /// units without a default instance, which can't be enabled)
+    pub wanted_by: Option<String>,
+
+    /// Instance enabled when a template unit is
+    pub default_instance: Option<String>,
+}
+
+impl SocketParams {
+    /// Render a systemd socket unit template at the given path (or default)
+    pub fn render(&self, template_path: Option<&Path>) -> Result<String, Error> {
+        let name = match template_path {
+            Some(p) => p.display().to_string(),
+            None => "(default:socket.hbs)".to_owned(),
+        };
+
+        let template = load_template(template_path, DEFAULT_SOCKET_TEMPLATE)?;
+        render_template(&name, &template, self)
+    }
+}
+
+/// Parameters passed to the systemd timer unit template
+#[derive(Serialize)]
+pub struct TimerParams {
+    /// Description of the timer
+    pub description: String,
+
+    /// Calendar events which trigger the timer
+    pub on_calendar: Option<String>,
+
+    /// Time after boot at which the timer triggers
+    pub on_boot_sec: Option<String>,
+
+    /// Time after its unit was last activated at which the timer triggers
+    pub on_unit_active_sec: Option<String>,
+
+    /// Trigger the timer on boot if it was missed while the system was down?
+    pub persistent: bool,
+
+    /// Unit activated by the timer
+    pub unit: String,
+
+    /// Target which wants the timer when it's enabled (none for template
+    /// units without a default instance, which can't be enabled)
+    pub wanted_by: Option<String>,
+
+    /// Instance enabled when a template unit is
+    pub default_instance: Option<String>,
+}
+
+impl TimerParams {
+    /// Render a systemd timer unit template at the given path (or default)
+    pub fn render(&self, template_path: Option<&Path>) -> Result<String, Error> {
+        let name = match template_path {
+            Some(p) => p.display().to_string(),
+            None => "(default:timer.hbs)".to_owned(),
+        };
+
+        let template = load_template(template_path, DEFAULT_TIMER_TEMPLATE)?;
+        render_template(&name, &template, self)
     }
 }
 
@@ -130,7 +281,19 @@ fn load_template(template_path: Option<&Path>, default_template: &str) -> Result
 /// Render a template
 fn render_template<T: Serialize>(name: &str, template: &str, data: &T) -> Result<String, Error> {
     let mut handlebars = Handlebars::new();
-    handlebars.register_template_string(name, template).unwrap();
+
+    // Templates render config files, not HTML
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars
+        .register_template_string(name, template)
+        .map_err(|e| {
+            format_err!(
+                ErrorKind::Template,
+                "error parsing template {}: {}",
+                name,
+                e
+            )
+        })?;
     Ok(handlebars
         .render(name, data)
         .map_err(|e| format_err!(ErrorKind::Template, "Error rendering template: {}", e))?)
@@ -0,0 +1,438 @@
+//! System users and groups created from `[package.metadata.rpm.users]`
+
+use crate::{
+    archive::{config_key, GeneratedFile},
+    config::{PackageConfig, RpmConfig, UserConfig},
+    dist::Dist,
+    error::{Error, ErrorKind},
+    prelude::*,
+    scriptlets::Scriptlets,
+};
+use std::{collections::BTreeSet, fs, path::Path};
+
+/// Directory in which sysusers.d snippets reside
+pub const SYSUSERS_DIR: &str = "/usr/lib/sysusers.d";
+
+/// Permissions of sysusers.d snippets
+const SYSUSERS_MODE: u32 = 0o644;
+
+/// Number of the spec source the sysusers.d snippet is shipped as for
+/// `%sysusers_create_compat`, high enough to stay clear of the spec's own
+const SYSUSERS_SOURCE: u32 = 100;
+
+/// Login shell of users without a configured one
+const DEFAULT_SHELL: &str = "/sbin/nologin";
+
+/// Users which exist on every system (created by the `setup` package)
+const SYSTEM_USERS: &[&str] = &[
+    "root", "bin", "daemon", "adm", "lp", "sync", "shutdown", "halt", "mail", "operator", "games",
+    "ftp", "nobody",
+];
+
+/// Groups which exist on every system (created by the `setup` package)
+const SYSTEM_GROUPS: &[&str] = &[
+    "root", "bin", "daemon", "sys", "adm", "tty", "disk", "lp", "mem", "kmem", "wheel", "cdrom",
+    "mail", "man", "dialout", "floppy", "games", "tape", "video", "ftp", "lock", "audio", "users",
+    "nobody", "utmp",
+];
+
+/// Write a sysusers.d snippet for the users configured in Cargo.toml into
+/// `output_dir`, adding the scriptlets which create them before the
+/// package's files are installed. Scriptlets which read the snippet get a
+/// copy in `sources_dir`, so the source RPM can be rebuilt.
+pub fn generate_users(
+    package: &PackageConfig,
+    output_dir: &Path,
+    sources_dir: &Path,
+    dist: Option<Dist>,
+    scriptlets: &mut Scriptlets,
+) -> Result<Vec<GeneratedFile>, Error> {
+    let rpm_metadata = match package.rpm_metadata() {
+        Some(rpm_metadata) => rpm_metadata,
+        None => return Ok(vec![]),
+    };
+
+    check_owners(rpm_metadata)?;
+
+    let users: Vec<(&String, &UserConfig)> = rpm_metadata
+        .users
+        .iter()
+        .flatten()
+        .filter(|(_, user)| !user.external.unwrap_or(false))
+        .collect();
+
+    if users.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut sysusers = format!(
+        "# System users of {}, created by systemd-sysusers\n",
+        package.rpm_name()
+    );
+
+    for (name, user) in &users {
+        let key = config_key("users", name);
+        sysusers.push_str(&sysusers_lines(name, user).map_err(|e| e.with_key(&key))?);
+    }
+
+    let file_name = format!("{}.conf", package.rpm_name());
+    let src_path = output_dir.join(&file_name);
+    fs::write(&src_path, &sysusers)?;
+
+    match dist {
+        Some(dist) if dist.supports_sysusers_compat() => {
+            let source_name = format!("{}.sysusers", package.rpm_name());
+            fs::create_dir_all(sources_dir)?;
+            fs::write(sources_dir.join(&source_name), &sysusers)?;
+
+            scriptlets.add_preamble(format!("Source{}: {}", SYSUSERS_SOURCE, source_name));
+            scriptlets.add_preamble("BuildRequires: systemd-rpm-macros");
+            scriptlets.add_preamble("%{?sysusers_requires_compat}");
+            scriptlets.pre.push(format!(
+                "%sysusers_create_compat %{{SOURCE{}}}",
+                SYSUSERS_SOURCE
+            ));
+        }
+        _ => {
+            let shadow_package = dist.map(Dist::shadow_package).unwrap_or("shadow-utils");
+            scriptlets.add_preamble(format!("Requires(pre): {}", shadow_package));
+
+            for (name, user) in &users {
+                add_useradd_lines(name, user, scriptlets);
+            }
+        }
+    }
+
+    Ok(vec![GeneratedFile {
+        src_path,
+        install_path: Path::new(SYSUSERS_DIR).join(file_name),
+        config_key: config_key("users", users[0].0),
+        mode: SYSUSERS_MODE,
+        noreplace: false,
+    }])
+}
+
+/// Check that every user and group which owns a file (including those
+/// created by tmpfiles.d) or runs a service is either a system default or
+/// configured in `[package.metadata.rpm.users]`
+pub fn check_owners(rpm_metadata: &RpmConfig) -> Result<(), Error> {
+    let mut users: BTreeSet<&str> = SYSTEM_USERS.iter().cloned().collect();
+    let mut groups: BTreeSet<&str> = SYSTEM_GROUPS.iter().cloned().collect();
+
+    for (name, user) in rpm_metadata.users.iter().flatten() {
+        users.insert(name);
+        groups.insert(name);
+        groups.extend(user.groups.iter().flatten().map(String::as_str));
+    }
+
+    let mut owners = vec![];
+
+    for (table, entries) in &[
+        ("targets", Some(&rpm_metadata.targets)),
+        ("files", rpm_metadata.files.as_ref()),
+    ] {
+        for (name, file) in entries.iter().cloned().flatten() {
+            owners.push((config_key(table, name), &file.username, &file.groupname));
+        }
+    }
+
+    for (name, unit) in rpm_metadata.systemd.iter().flatten() {
+        owners.push((config_key("systemd", name), &unit.user, &unit.group));
+    }
+
+    for (path, entry) in rpm_metadata.tmpfiles.iter().flatten() {
+        owners.push((config_key("tmpfiles", path), &entry.user, &entry.group));
+    }
+
+    let mut problems = vec![];
+
+    for (key, user, group) in owners {
+        if let Some(user) = user {
+            if !users.contains(user.as_str()) {
+                problems.push(format!(
+                    "{}: user {:?} is neither a system user nor in [package.metadata.rpm.users]",
+                    key, user
+                ));
+            }
+        }
+
+        if let Some(group) = group {
+            if !groups.contains(group.as_str()) {
+                problems.push(format!(
+                    "{}: group {:?} is neither a system group nor created by [package.metadata.rpm.users]",
+                    key, group
+                ));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        fail!(
+            ErrorKind::Config,
+            "unknown owners in Cargo.toml:\n  - {}",
+            problems.join("\n  - ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Lines of a sysusers.d snippet which create a user and add it to its
+/// supplementary groups
+fn sysusers_lines(name: &str, user: &UserConfig) -> Result<String, Error> {
+    check_name(name)?;
+
+    for group in user.groups.iter().flatten() {
+        check_name(group)?;
+    }
+
+    let description = match user.description {
+        Some(ref description) if description.contains(&['"', '\n'][..]) => fail!(
+            ErrorKind::Config,
+            "description {:?} contains a quote or newline",
+            description
+        ),
+        Some(ref description) => format!("\"{}\"", description),
+        None => "-".to_owned(),
+    };
+
+    let mut paths = vec![];
+
+    for (option, path) in &[("home", &user.home), ("shell", &user.shell)] {
+        paths.push(match path {
+            Some(path) => {
+                let path_str = path.to_string_lossy();
+
+                if !path.is_absolute() || path_str.contains(char::is_whitespace) {
+                    fail!(
+                        ErrorKind::Config,
+                        "`{}` must be an absolute path without whitespace: {}",
+                        option,
+                        path.display()
+                    );
+                }
+
+                path_str.into_owned()
+            }
+            None => "-".to_owned(),
+        });
+    }
+
+    let uid = user
+        .uid
+        .map_or_else(|| "-".to_owned(), |uid| uid.to_string());
+    let mut lines = format!(
+        "u {} {} {} {} {}\n",
+        name, uid, description, paths[0], paths[1]
+    );
+
+    for group in user.groups.iter().flatten() {
+        lines.push_str(&format!("m {} {}\n", name, group));
+    }
+
+    Ok(lines)
+}
+
+/// Check that a user or group name is one `useradd` accepts everywhere
+fn check_name(name: &str) -> Result<(), Error> {
+    let valid = name.chars().enumerate().all(|(i, c)| {
+        c == '_' || c.is_ascii_lowercase() || (i > 0 && (c == '-' || c.is_ascii_digit()))
+    });
+
+    if name.is_empty() || name.len() > 32 || !valid {
+        fail!(
+            ErrorKind::Config,
+            "invalid user or group name {:?} (expected lowercase letters, digits, `-` and `_`)",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+/// Add `%pre` lines which create a user with `groupadd`/`useradd`, for
+/// distributions without `%sysusers_create_compat`
+fn add_useradd_lines(name: &str, user: &UserConfig, scriptlets: &mut Scriptlets) {
+    let groups = user.groups.clone().unwrap_or_default();
+
+    for group in &groups {
+        scriptlets.pre.push(format!(
+            "getent group {0} >/dev/null || groupadd -r {0}",
+            group
+        ));
+    }
+
+    let id = user.uid.map(|uid| uid.to_string());
+    let mut groupadd = vec!["groupadd", "-r"];
+    let mut useradd = vec!["useradd", "-r"];
+
+    if let Some(ref id) = id {
+        groupadd.extend(&["-g", id]);
+        useradd.extend(&["-u", id]);
+    }
+
+    let home = user
+        .home
+        .as_ref()
+        .map_or_else(|| "/".to_owned(), |home| home.display().to_string());
+    let shell = user.shell.as_ref().map_or_else(
+        || DEFAULT_SHELL.to_owned(),
+        |shell| shell.display().to_string(),
+    );
+    let supplementary = groups.join(",");
+    let description = user
+        .description
+        .as_ref()
+        .map(|description| format!("'{}'", description.replace('\'', r"'\''")));
+
+    useradd.extend(&["-g", name, "-d", &home, "-M", "-s", &shell]);
+
+    if !supplementary.is_empty() {
+        useradd.extend(&["-G", &supplementary]);
+    }
+
+    if let Some(ref description) = description {
+        useradd.extend(&["-c", description]);
+    }
+
+    groupadd.push(name);
+    useradd.push(name);
+
+    scriptlets.pre.push(format!(
+        "getent group {} >/dev/null || {}",
+        name,
+        groupadd.join(" ")
+    ));
+
+    // A `%` in the description would start a macro in the spec
+    scriptlets.pre.push(
+        format!("getent passwd {} >/dev/null || {}", name, useradd.join(" ")).replace('%', "%%"),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> UserConfig {
+        UserConfig {
+            description: Some("Foo daemon".to_owned()),
+            uid: Some(850),
+            home: Some("/var/lib/foo".into()),
+            groups: Some(vec!["adm".to_owned(), "foo-data".to_owned()]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sysusers_source() {
+        let package: PackageConfig = serde_json::from_str(
+            r#"{
+                "name": "foo",
+                "version": "1.0.0",
+                "description": "Foo daemon",
+                "license": "MIT",
+                "metadata": {
+                    "rpm": {
+                        "targets": { "foo": { "path": "/usr/bin/foo" } },
+                        "users": { "foo": {} }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("cargo-rpm-users-{}", std::process::id()));
+        let (output_dir, sources_dir) = (dir.join("generated"), dir.join("SOURCES"));
+        fs::create_dir_all(&output_dir).unwrap();
+
+        let mut scriptlets = Scriptlets::default();
+        let generated = generate_users(
+            &package,
+            &output_dir,
+            &sources_dir,
+            Some(Dist::Fedora),
+            &mut scriptlets,
+        )
+        .unwrap();
+
+        // The snippet is installed, and shipped as a source for `%pre`
+        assert_eq!(
+            generated[0].install_path,
+            Path::new("/usr/lib/sysusers.d/foo.conf")
+        );
+        assert_eq!(
+            fs::read_to_string(sources_dir.join("foo.sysusers")).unwrap(),
+            fs::read_to_string(&generated[0].src_path).unwrap()
+        );
+        assert_eq!(scriptlets.preamble[0], "Source100: foo.sysusers");
+        assert_eq!(scriptlets.pre, ["%sysusers_create_compat %{SOURCE100}"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn names() {
+        assert!(check_name("foo").is_ok());
+        assert!(check_name("_foo-bar2").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("Foo").is_err());
+        assert!(check_name("-foo").is_err());
+        assert!(check_name("2foo").is_err());
+        assert!(check_name("foo.bar").is_err());
+        assert!(check_name(&"a".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn sysusers() {
+        assert_eq!(
+            sysusers_lines("foo", &user()).unwrap(),
+            "u foo 850 \"Foo daemon\" /var/lib/foo -\nm foo adm\nm foo foo-data\n"
+        );
+        assert_eq!(
+            sysusers_lines("foo", &UserConfig::default()).unwrap(),
+            "u foo - - - -\n"
+        );
+
+        let quoted = UserConfig {
+            description: Some("\"Foo\"".to_owned()),
+            ..Default::default()
+        };
+        assert!(sysusers_lines("foo", &quoted).is_err());
+
+        let relative = UserConfig {
+            home: Some("var/lib/foo".into()),
+            ..Default::default()
+        };
+        assert!(sysusers_lines("foo", &relative).is_err());
+        assert!(sysusers_lines("Foo", &UserConfig::default()).is_err());
+    }
+
+    #[test]
+    fn useradd() {
+        let mut scriptlets = Scriptlets::default();
+        add_useradd_lines("foo", &user(), &mut scriptlets);
+        assert_eq!(
+            scriptlets.pre,
+            [
+                "getent group adm >/dev/null || groupadd -r adm",
+                "getent group foo-data >/dev/null || groupadd -r foo-data",
+                "getent group foo >/dev/null || groupadd -r -g 850 foo",
+                "getent passwd foo >/dev/null || useradd -r -u 850 -g foo -d /var/lib/foo -M \
+                 -s /sbin/nologin -G adm,foo-data -c 'Foo daemon' foo",
+            ]
+        );
+
+        let quoted = UserConfig {
+            description: Some("Foo's 100% daemon".to_owned()),
+            ..Default::default()
+        };
+        let mut scriptlets = Scriptlets::default();
+        add_useradd_lines("foo", &quoted, &mut scriptlets);
+        assert_eq!(
+            scriptlets.pre[1],
+            "getent passwd foo >/dev/null || useradd -r -g foo -d / -M -s /sbin/nologin \
+             -c 'Foo'\\''s 100%% daemon' foo"
+        );
+    }
+}